@@ -1,8 +1,12 @@
+use std::io::prelude::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
 use futures::stream::StreamExt;
 use matrix_sdk;
+use matrix_sdk::uuid::Uuid;
 use signal_hook_tokio::Signals;
 use termion::input::TermRead;
-use termion::raw::IntoRawMode;
+use termion::raw::{IntoRawMode, RawTerminal};
 use termion::screen::AlternateScreen;
 
 use crate::events;
@@ -10,7 +14,12 @@ use crate::matrix;
 use crate::state;
 use crate::ui;
 
-pub async fn tui(mut client: matrix_sdk::Client) -> Result<(), Box<dyn std::error::Error>> {
+/// Returns whether the user asked to log out.
+pub async fn tui(
+    mut client: matrix_sdk::Client,
+    config: crate::config::UiConfig,
+    store_path: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
     // SETUP COMMUNICATION
     let (matrix_tx, mut matrix_rx) = tokio::sync::mpsc::unbounded_channel();
     let (mient_tx, mut mient_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -20,17 +29,26 @@ pub async fn tui(mut client: matrix_sdk::Client) -> Result<(), Box<dyn std::erro
         .await;
 
     // SETUP TERMINAL
-    let stdout = std::io::stdout().into_raw_mode()?;
-    let stdout = AlternateScreen::from(stdout);
+    // raw mode is kept apart to be able to suspend it while an external editor runs
+    let raw_mode = std::io::stdout().into_raw_mode()?;
+    let stdout = AlternateScreen::from(std::io::stdout());
     let backend = tui::backend::TermionBackend::new(stdout);
     let mut terminal = tui::Terminal::new(backend)?;
 
     // SETUP LOCAL STATE
-    let mut state = state::State::new(client.clone(), matrix_tx.clone(), terminal.size()?).await;
+    let mut state = state::State::new(
+        client.clone(),
+        config,
+        matrix_tx.clone(),
+        terminal.size()?,
+        store_path,
+    )
+    .await;
 
     // EVENT LOOP
     spawn_matrix_sync_task(client.clone(), matrix::MatrixBroker::new(matrix_tx.clone()));
-    let input_handle = spawn_input_task(mient_tx.clone());
+    let (resume_input_tx, resume_input_rx) = std::sync::mpsc::channel();
+    spawn_input_thread(mient_tx.clone(), resume_input_rx);
 
     let sigwinch_signals = Signals::new(&[signal_hook::consts::SIGWINCH])?;
     let sigwinch_handle = sigwinch_signals.handle();
@@ -49,9 +67,23 @@ pub async fn tui(mut client: matrix_sdk::Client) -> Result<(), Box<dyn std::erro
         ui::draw(&mut terminal, &mut state)?;
         tokio::select! {
             event = mient_rx.recv() => {
-                if !events::handle_mient_event(event.unwrap(), &mut state, &mut client, &event_tx).await {
+                let event = event.unwrap();
+                let pauses_input = matches!(
+                    event,
+                    events::MientEvent::Keyboard(events::EXTERNAL_EDITOR_KEY)
+                );
+                if !events::handle_mient_event(event, &mut state, &mut client, &event_tx).await {
                     break;
                 }
+                if state.external_editor {
+                    state.external_editor = false;
+                    if let Err(e) = edit_externally(&mut terminal, &raw_mode, &mut state) {
+                        state.status = format!("External editor failed: {}", e);
+                    }
+                }
+                if pauses_input {
+                    let _ = resume_input_tx.send(());
+                }
             }
             event = matrix_rx.recv() => {
                 events::handle_matrix_event(event.unwrap(), &mut state).await;
@@ -62,13 +94,75 @@ pub async fn tui(mut client: matrix_sdk::Client) -> Result<(), Box<dyn std::erro
     sigwinch_handle.close();
     matrix_rx.close();
     mient_rx.close();
-    let _ = tokio::join!(input_handle);
     drop(terminal);
+    drop(raw_mode);
+
+    Ok(state.logout)
+}
+
+/// Hands the terminal over to `$EDITOR` to edit the input, along with the quoted message we are
+/// replying to, if any.
+fn edit_externally<W: Write>(
+    terminal: &mut tui::Terminal<tui::backend::TermionBackend<W>>,
+    raw_mode: &RawTerminal<std::io::Stdout>,
+    state: &mut state::State,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut quote = String::new();
+    if let Some(room) = state.current_room() {
+        if let Some(msg) = room.selected_message() {
+            quote.push_str(&format!("> <{}>\n", msg.event.sender));
+            for line in crate::utils::format_message_body(msg.content()).lines() {
+                quote.push_str(&format!("> {}\n", line));
+            }
+            quote.push('\n');
+        }
+    }
+
+    // only we should be able to read what is being written
+    let path = std::env::temp_dir().join(format!("mient-{}.md", Uuid::new_v4()));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| write!(f, "{}{}", quote, state.input.text()))?;
 
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let mut editor = editor.split_whitespace();
+    let mut command = std::process::Command::new(editor.next().unwrap_or("vi"));
+    command.args(editor).arg(&path);
+
+    write!(terminal.backend_mut(), "{}", termion::screen::ToMainScreen)?;
+    terminal.backend_mut().flush()?;
+    raw_mode.suspend_raw_mode()?;
+    let status = command.status();
+    raw_mode.activate_raw_mode()?;
+    write!(
+        terminal.backend_mut(),
+        "{}",
+        termion::screen::ToAlternateScreen
+    )?;
+    terminal.backend_mut().flush()?;
+    // the screen has to be redrawn from scratch
+    terminal.clear()?;
+
+    let mut edited = String::new();
+    let read = std::fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut edited));
+    let _ = std::fs::remove_file(&path);
+    if !status?.success() {
+        return Err("the editor exited with an error".into());
+    }
+    read?;
+    // the quote is only there for context, the reply fallback is added when sending, but a quote
+    // that was changed is part of the message
+    let text = edited.strip_prefix(quote.as_str()).unwrap_or(&edited);
+    // editors end the file with a new line
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    state.input.set_text(text.to_owned());
     Ok(())
 }
 
-fn spawn_matrix_sync_task(
+pub fn spawn_matrix_sync_task(
     client: matrix_sdk::Client,
     matrix_broker: matrix::MatrixBroker,
 ) -> tokio::task::JoinHandle<()> {
@@ -85,10 +179,13 @@ fn spawn_matrix_sync_task(
     })
 }
 
-fn spawn_input_task(
+// Reading stdin blocks, so this gets its own thread that doesn't keep us from exiting if we stop
+// for any other reason than Esc.
+fn spawn_input_thread(
     tx: tokio::sync::mpsc::UnboundedSender<events::MientEvent>,
-) -> tokio::task::JoinHandle<()> {
-    tokio::task::spawn(async move {
+    resume_rx: std::sync::mpsc::Receiver<()>,
+) {
+    std::thread::spawn(move || {
         for key in std::io::stdin().keys().flatten() {
             if tx.send(events::MientEvent::Keyboard(key)).is_err() {
                 return;
@@ -96,6 +193,10 @@ fn spawn_input_task(
             if key == termion::event::Key::Esc {
                 return;
             }
+            // don't steal the keys of the external editor
+            if key == events::EXTERNAL_EDITOR_KEY && resume_rx.recv().is_err() {
+                return;
+            }
         }
-    })
+    });
 }
@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use crate::export::ExportFormat;
+
+#[derive(StructOpt)]
+#[structopt(name = "mient", about = "A terminal Matrix client")]
+pub struct Opt {
+    /// Config file, defaults to ~/.config/mient/config.json
+    #[structopt(long, global = true)]
+    pub config: Option<String>,
+    /// Store directory, overrides the one of the account
+    #[structopt(long, global = true)]
+    pub store: Option<String>,
+    /// Name of the account to use, defaults to the first one of the config
+    #[structopt(long, global = true)]
+    pub account: Option<String>,
+    /// Log in with this token if the saved session can't be restored
+    #[structopt(long, global = true)]
+    pub login_token: Option<String>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Runs the terminal interface, the default
+    Tui,
+    /// Imports or exports the room keys
+    Keys {
+        #[structopt(subcommand)]
+        command: KeysCommand,
+    },
+    /// Manages the devices of the account
+    Devices {
+        #[structopt(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Bootstraps cross-signing for the account
+    CrossSign,
+    /// Logs out and wipes the store of the account
+    Logout,
+    /// Sends a message and prints its event id
+    Send {
+        /// Room id or alias
+        room: String,
+        /// Message to send, read from stdin if absent or -
+        text: Option<String>,
+        /// Sends a notice, like bots do
+        #[structopt(long, conflicts_with = "emote")]
+        notice: bool,
+        /// Sends an emote, like /me
+        #[structopt(long)]
+        emote: bool,
+        /// Formats the message as CommonMark
+        #[structopt(long)]
+        markdown: bool,
+        /// Event id of the message to reply to
+        #[structopt(long)]
+        reply_to: Option<String>,
+    },
+    /// Lists, joins, leaves and creates rooms
+    Rooms {
+        #[structopt(subcommand)]
+        command: RoomsCommand,
+    },
+    /// Exports the history of a room
+    Export {
+        /// Room id or alias
+        room: String,
+        /// json (raw events), text or html
+        #[structopt(long, default_value = "text")]
+        format: ExportFormat,
+        /// Only exports the messages since this date, as YYYY-MM-DD
+        #[structopt(long)]
+        since: Option<String>,
+        /// File to write to instead of stdout
+        #[structopt(long, short)]
+        output: Option<String>,
+    },
+    /// Prints the incoming events as JSON, one per line
+    Tail {
+        /// Only prints the events of this room, by id or alias
+        #[structopt(long)]
+        room: Option<String>,
+    },
+}
+
+#[derive(StructOpt)]
+pub enum KeysCommand {
+    /// Imports room keys from a file
+    Import { file: PathBuf, password: String },
+    /// Exports all the room keys to a file
+    Export { file: PathBuf, password: String },
+}
+
+#[derive(StructOpt)]
+pub enum DevicesCommand {
+    /// Lists the devices and their trust state
+    List,
+    /// Interactively verifies a device
+    Verify { device: String },
+    /// Deletes a device, other than the current one
+    Delete { device: String },
+}
+
+#[derive(StructOpt)]
+pub enum RoomsCommand {
+    /// Lists the joined, invited and left rooms as JSON, one per line
+    List,
+    /// Joins a room, or accepts an invitation
+    Join {
+        /// Room id or alias
+        room: String,
+    },
+    /// Leaves a room, or rejects an invitation
+    Leave {
+        /// Room id or alias
+        room: String,
+    },
+    /// Creates a room and prints its id
+    Create {
+        #[structopt(long)]
+        name: Option<String>,
+        /// Enables end-to-end encryption
+        #[structopt(long)]
+        encrypted: bool,
+        /// Users to invite
+        #[structopt(long)]
+        invite: Vec<String>,
+    },
+}
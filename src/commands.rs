@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::future::Future;
+
+use matrix_sdk::ruma::{
+    api::{
+        client::error::ErrorKind,
+        error::{FromHttpResponseError, ServerError},
+    },
+    events::{room::topic::TopicEventContent, AnyStateEventContent},
+    RoomId, RoomIdOrAliasId, UserId,
+};
+
+use crate::events::MatrixEvent;
+use crate::state::State;
+use crate::utils::MessageKind;
+
+/// Names, arguments and descriptions of the commands typed in the input line with a leading `/`.
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    ("join", "<room>", "joins a room by id or alias"),
+    ("leave", "", "leaves the current room"),
+    ("me", "<text>", "sends an emote"),
+    ("notice", "<text>", "sends a notice"),
+    (
+        "plain",
+        "<text>",
+        "sends a message without rendering markdown",
+    ),
+    (
+        "markdown",
+        "<text>",
+        "sends a message rendered from markdown",
+    ),
+    ("topic", "<topic>", "changes the topic of the current room"),
+    ("nick", "<name>", "changes our display name"),
+    ("invite", "<user>", "invites a user to the current room"),
+    (
+        "kick",
+        "<user> [reason]",
+        "kicks a user from the current room",
+    ),
+    (
+        "ban",
+        "<user> [reason]",
+        "bans a user from the current room",
+    ),
+    ("query", "<user>", "opens a direct chat with a user"),
+    ("logout", "", "quits, logs out and wipes the store"),
+    ("help", "[command]", "shows the commands or how to use one"),
+];
+
+fn usage(command: &str) -> String {
+    match COMMANDS.iter().find(|(name, _, _)| *name == command) {
+        Some((name, args, description)) => format!("/{} {}: {}", name, args, description),
+        None => format!("Unknown command /{}, see /help", command),
+    }
+}
+
+/// Runs the command in the background, reporting its failure in the status line.
+pub fn spawn_command<F, E>(tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>, command: F)
+where
+    F: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Display,
+{
+    let tx = tx.clone();
+    tokio::task::spawn(async move {
+        if let Err(e) = command.await {
+            if let Err(e) = tx.send(MatrixEvent::Status {
+                message: e.to_string(),
+            }) {
+                crate::log::error(&e.to_string());
+            }
+        }
+    });
+}
+
+fn split_user(args: &str) -> Result<(UserId, Option<String>), String> {
+    let (user, rest) = match args.split_once(' ') {
+        Some((user, rest)) => (user, Some(rest.trim().to_owned()).filter(|r| !r.is_empty())),
+        None => (args, None),
+    };
+    UserId::try_from(user)
+        .map(|user_id| (user_id, rest))
+        .map_err(|e| format!("Invalid user {}: {}", user, e))
+}
+
+fn command_needs_room(command: &str) -> bool {
+    matches!(
+        command,
+        "leave" | "me" | "notice" | "plain" | "markdown" | "topic" | "invite" | "kick" | "ban"
+    )
+}
+
+/// Whether the input line is sent to the current room or runs a command that acts on it.
+pub fn needs_room(line: &str) -> bool {
+    match line.strip_prefix('/') {
+        Some(command) if !command.starts_with('/') => {
+            command_needs_room(command.split(' ').next().unwrap_or(""))
+        }
+        _ => true,
+    }
+}
+
+/// Runs a command line, without its leading `/`. Returns false if mient should quit.
+pub fn run(
+    command_line: &str,
+    state: &mut State,
+    client: &matrix_sdk::Client,
+    tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+) -> bool {
+    let (command, args) = match command_line.split_once(' ') {
+        Some((command, args)) => (command, args.trim()),
+        None => (command_line, ""),
+    };
+    let takes_args = COMMANDS
+        .iter()
+        .find(|(name, _, _)| *name == command)
+        .map(|(_, args, _)| !args.is_empty() && !args.starts_with('['));
+    if takes_args == Some(true) && args.is_empty() {
+        state.status = usage(command);
+        return true;
+    }
+
+    let room = state
+        .current_room()
+        .and_then(|r| client.get_joined_room(&r.id));
+    if command_needs_room(command) && room.is_none() {
+        state.status = String::from("Not in a room");
+        return true;
+    }
+
+    match command {
+        "logout" => {
+            state.logout = true;
+            return false;
+        }
+        "help" if args.is_empty() => {
+            state.status = COMMANDS
+                .iter()
+                .map(|(name, _, _)| format!("/{}", name))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+        "help" => state.status = usage(args.trim_start_matches('/')),
+        "me" | "notice" | "plain" | "markdown" => {
+            let (kind, markdown) = match command {
+                "me" => (MessageKind::Emote, state.config.markdown),
+                "notice" => (MessageKind::Notice, state.config.markdown),
+                "plain" => (MessageKind::Text, false),
+                _ => (MessageKind::Text, true),
+            };
+            crate::matrix::send_message(state, args, kind, markdown)
+        }
+        "join" => {
+            let room = match RoomIdOrAliasId::try_from(args) {
+                Ok(r) => r,
+                Err(e) => {
+                    state.status = format!("Invalid room {}: {}", args, e);
+                    return true;
+                }
+            };
+            let client = client.clone();
+            let event_tx = tx.clone();
+            spawn_command(tx, async move {
+                let response = client.join_room_by_id_or_alias(&room, &[]).await?;
+                let name = match client.get_room(&response.room_id) {
+                    Some(room) => room.display_name().await?,
+                    None => response.room_id.to_string(),
+                };
+                event_tx.send(MatrixEvent::RoomName {
+                    id: response.room_id,
+                    name,
+                })?;
+                Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+            });
+        }
+        "leave" => {
+            let room = room.unwrap();
+            spawn_command(tx, async move { room.leave().await.map(|_| ()) });
+        }
+        "topic" => {
+            let room = room.unwrap();
+            let content = AnyStateEventContent::RoomTopic(TopicEventContent::new(args.to_owned()));
+            spawn_command(tx, async move {
+                room.send_state_event(content, "").await.map(|_| ())
+            });
+        }
+        "nick" => {
+            let client = client.clone();
+            let name = args.to_owned();
+            spawn_command(
+                tx,
+                async move { client.set_display_name(Some(&name)).await },
+            );
+        }
+        "invite" | "kick" | "ban" | "query" => {
+            let (user_id, reason) = match split_user(args) {
+                Ok(u) => u,
+                Err(e) => {
+                    state.status = e;
+                    return true;
+                }
+            };
+            match command {
+                "invite" => {
+                    let room = room.unwrap();
+                    spawn_command(tx, async move {
+                        room.invite_user_by_id(&user_id).await.map(|_| ())
+                    });
+                }
+                "kick" => {
+                    let room = room.unwrap();
+                    spawn_command(tx, async move {
+                        room.kick_user(&user_id, reason.as_deref())
+                            .await
+                            .map(|_| ())
+                    });
+                }
+                "ban" => {
+                    let room = room.unwrap();
+                    spawn_command(tx, async move {
+                        room.ban_user(&user_id, reason.as_deref()).await.map(|_| ())
+                    });
+                }
+                _ => query(user_id, state, client, tx),
+            }
+        }
+        _ => state.status = usage(command),
+    }
+    true
+}
+
+/// Switches to the direct chat with that user, creating it if there is none.
+fn query(
+    user_id: UserId,
+    state: &mut State,
+    client: &matrix_sdk::Client,
+    tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+) {
+    let direct_room = client
+        .joined_rooms()
+        .into_iter()
+        .find(|r| r.direct_target().as_ref() == Some(&user_id));
+    if let Some(direct_room) = direct_room {
+        if let Some(index) = state
+            .rooms
+            .iter()
+            .position(|r| &r.id == direct_room.room_id())
+        {
+            state.set_current_room(index);
+            return;
+        }
+        // joined but not listed yet
+        let event_tx = tx.clone();
+        spawn_command(tx, async move {
+            event_tx.send(MatrixEvent::OpenRoom {
+                id: direct_room.room_id().clone(),
+                name: direct_room.display_name().await?,
+            })?;
+            Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+        });
+        return;
+    }
+
+    let client = client.clone();
+    let own_user_id = state.user_id.clone();
+    let event_tx = tx.clone();
+    spawn_command(tx, async move {
+        use matrix_sdk::ruma::api::client::r0::room::create_room::{Request, RoomPreset};
+        let invite = [user_id.clone()];
+        let mut request = Request::new();
+        request.invite = &invite;
+        request.is_direct = true;
+        request.preset = Some(RoomPreset::TrustedPrivateChat);
+        let response = client.create_room(request).await?;
+        add_direct_room(&client, &own_user_id, &user_id, &response.room_id).await?;
+        let name = match client.get_room(&response.room_id) {
+            Some(room) => room.display_name().await?,
+            None => response.room_id.to_string(),
+        };
+        event_tx.send(MatrixEvent::OpenRoom {
+            id: response.room_id,
+            name,
+        })?;
+        Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    });
+}
+
+/// Adds the room to the `m.direct` account data, where the clients look for direct chats.
+async fn add_direct_room(
+    client: &matrix_sdk::Client,
+    own_user_id: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    use matrix_sdk::ruma::api::client::r0::config::{
+        get_global_account_data, set_global_account_data,
+    };
+    let request = get_global_account_data::Request::new(own_user_id, "m.direct");
+    let mut direct = match client.send(request, None).await {
+        Ok(response) => serde_json::from_str::<BTreeMap<String, Vec<String>>>(
+            response.account_data.json().get(),
+        )?,
+        // nobody had a direct chat yet
+        Err(e) if not_found(&e) => BTreeMap::new(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let rooms = direct.entry(user_id.to_string()).or_default();
+    if rooms.iter().any(|r| r == room_id.as_str()) {
+        return Ok(());
+    }
+    rooms.push(room_id.to_string());
+    let data = serde_json::value::to_raw_value(&direct)?;
+    let request = set_global_account_data::Request::new(&data, "m.direct", own_user_id);
+    client.send(request, None).await?;
+    Ok(())
+}
+
+fn not_found(error: &matrix_sdk::HttpError) -> bool {
+    match error {
+        matrix_sdk::HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))) => {
+            matches!(e.kind, ErrorKind::NotFound)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,79 @@
+use crate::state::State;
+
+/// Completes the word before the cursor, or cycles through the candidates if we already are.
+pub fn complete(state: &mut State, client: &matrix_sdk::Client, forward: bool) {
+    if state.input.is_completing() {
+        state.input.cycle_completion(forward);
+        return;
+    }
+    let (start, word) = state.input.word_before_cursor();
+    let candidates = candidates(word, start == 0, state, client);
+    state.input.complete(start, candidates);
+}
+
+/// What `word` can be completed to: members, room aliases, commands or emoji.
+fn candidates(
+    word: &str,
+    at_start: bool,
+    state: &State,
+    client: &matrix_sdk::Client,
+) -> Vec<String> {
+    let mut candidates = Vec::new();
+    if let Some(prefix) = word.strip_prefix('/').filter(|_| at_start) {
+        candidates.extend(
+            crate::commands::COMMANDS
+                .iter()
+                .filter(|(name, _, _)| name.starts_with(prefix))
+                .map(|(name, _, _)| format!("/{} ", name)),
+        );
+    } else if let Some(prefix) = word.strip_prefix('@') {
+        let prefix = prefix.to_lowercase();
+        // fetched in the background, asking the server here would block the interface
+        let members = state.current_room().map(|room| &room.members);
+        // mentions starting the message are addressed to that member
+        let suffix = if at_start { ": " } else { " " };
+        // links to the members are rendered as mentions by the other clients
+        let link = state.config.markdown && !state.input.text().starts_with("/plain ");
+        for (user_id, display_name) in members.into_iter().flat_map(|m| m.present()) {
+            if user_id == &state.user_id {
+                continue;
+            }
+            let matches = user_id.localpart().to_lowercase().starts_with(&prefix)
+                || display_name.map_or(false, |name| name.to_lowercase().starts_with(&prefix));
+            if matches {
+                let name = display_name.map_or_else(|| user_id.to_string(), str::to_owned);
+                if link {
+                    candidates.push(format!(
+                        "[{}](https://matrix.to/#/{}){}",
+                        crate::utils::escape_markdown(&name),
+                        user_id,
+                        suffix
+                    ));
+                } else {
+                    candidates.push(format!("{}{}", name, suffix));
+                }
+            }
+        }
+        candidates.sort_by_key(|c| c.to_lowercase());
+    } else if word.starts_with('#') {
+        let word = word.to_lowercase();
+        for room in client.joined_rooms() {
+            let aliases = room.canonical_alias().into_iter().chain(room.alt_aliases());
+            for alias in aliases {
+                if alias.as_str().to_lowercase().starts_with(&word) {
+                    candidates.push(format!("{} ", alias));
+                }
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+    } else if let Some(shortcode) = word.strip_prefix(':').filter(|s| !s.is_empty()) {
+        let shortcode = shortcode.trim_end_matches(':');
+        for (name, emoji) in crate::emoji::EMOJI {
+            if name.starts_with(shortcode) && !candidates.iter().any(|c| c == emoji) {
+                candidates.push(emoji.to_string());
+            }
+        }
+    }
+    candidates
+}
@@ -1,3 +1,4 @@
+use matrix_sdk::ruma::{RoomAliasId, RoomId};
 use serde::Deserialize;
 use std::io::prelude::Read;
 
@@ -15,47 +16,176 @@ impl std::fmt::Display for MientConfigError {
 impl std::error::Error for MientConfigError {}
 
 #[derive(Deserialize)]
-struct UserConfig {
+struct AccountUserConfig {
+    name: Option<String>,
     user: String,
     homeserver: String,
-    device_id: String, // TODO option and make one/write it out if absent
-    password_cmd: Vec<String>,
+    device_id: Option<String>,
+    password_cmd: Option<Vec<String>>,
+    store_path: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct MientConfig {
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UserConfig {
+    Accounts { accounts: Vec<AccountUserConfig> },
+    // the original format, with a single account
+    Single(AccountUserConfig),
+}
+
+#[derive(Debug)]
+pub struct AccountConfig {
+    pub name: String,
     pub user: String,
     pub homeserver: String,
-    pub password: String,
-    pub device_id: String, // TODO option and make one/write it out if absent
+    /// Only used for the first login, the device is then saved along with the session.
+    pub device_id: Option<String>,
+    pub store_path: String,
+    /// Accounts without one have to log in with single sign-on or a login token.
+    password_cmd: Option<Vec<String>>,
+}
+
+/// Settings of the terminal interface, at the top level of the config, shared by the accounts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UiConfig {
+    /// Whether the messages are sent as markdown, `/plain` and `/markdown` override it.
+    pub markdown: bool,
+    /// Name of a theme bundled with syntect or path of a `.tmTheme` file, to highlight code.
+    pub syntax_theme: String,
+    /// Rooms, by id or alias, where joins, leaves, name changes and such aren't shown.
+    pub hide_state_changes: Vec<String>,
+    /// Whether membership changes in a row are grouped on one line.
+    pub group_membership_changes: bool,
+}
+
+impl UiConfig {
+    pub fn shows_state_changes(&self, room_id: &RoomId, alias: Option<&RoomAliasId>) -> bool {
+        !self.hide_state_changes.iter().any(|room| {
+            room == room_id.as_str() || alias.map_or(false, |alias| room == alias.as_str())
+        })
+    }
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig {
+            markdown: true,
+            syntax_theme: String::from(crate::highlight::DEFAULT_THEME),
+            hide_state_changes: Vec::new(),
+            group_membership_changes: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MientConfig {
+    pub accounts: Vec<AccountConfig>,
+    pub ui: UiConfig,
 }
 
 impl MientConfig {
-    pub fn get(config_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// `data_path` is where the account stores go unless they have their own `store_path`.
+    pub fn get(config_path: &str, data_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let mut file = std::fs::File::open(config_path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
         let user_config = serde_json::from_str::<UserConfig>(&contents)?;
-        Self::make(user_config)
+        let ui = serde_json::from_str::<UiConfig>(&contents)?;
+        Self::make(user_config, ui, data_path)
     }
 
-    fn make(user_config: UserConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        if user_config.password_cmd.is_empty() {
+    fn make(
+        user_config: UserConfig,
+        ui: UiConfig,
+        data_path: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let accounts = match user_config {
+            UserConfig::Accounts { accounts } => accounts
+                .into_iter()
+                .map(|account| {
+                    let name = account.name.clone().unwrap_or_else(|| account.user.clone());
+                    let store_path = format!("{}/{}", data_path, name);
+                    AccountConfig::make(account, name, store_path)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            // keep using the store from before multiple accounts were supported
+            UserConfig::Single(account) => {
+                let name = account.name.clone().unwrap_or_else(|| account.user.clone());
+                vec![AccountConfig::make(account, name, data_path.to_string())?]
+            }
+        };
+        if accounts.is_empty() {
             return Err(Box::new(MientConfigError {
-                message: String::from("Invalid password command"),
+                message: String::from("No account configured"),
             }));
         }
-        let password = std::process::Command::new(&user_config.password_cmd[0])
-            .args(&user_config.password_cmd[1..])
-            .output()?
-            .stdout;
-        let password = String::from_utf8(password)?.trim().into();
-        let config = MientConfig {
+        // accounts sharing a store would overwrite each other's session and keys
+        for (i, account) in accounts.iter().enumerate() {
+            let store_path = std::path::Path::new(&account.store_path);
+            for other in &accounts[..i] {
+                let message = if other.name == account.name {
+                    format!("Several accounts are named {}", account.name)
+                } else if std::path::Path::new(&other.store_path) == store_path {
+                    format!(
+                        "The accounts {} and {} use the same store {}",
+                        other.name, account.name, account.store_path
+                    )
+                } else {
+                    continue;
+                };
+                return Err(Box::new(MientConfigError { message }));
+            }
+        }
+        Ok(MientConfig { accounts, ui })
+    }
+
+    /// Returns the account with the given name, or the first one.
+    pub fn account(self, name: Option<&str>) -> Result<AccountConfig, MientConfigError> {
+        let mut accounts = self.accounts.into_iter();
+        match name {
+            None => Ok(accounts.next().unwrap()),
+            Some(name) => accounts
+                .find(|a| a.name == name)
+                .ok_or_else(|| MientConfigError {
+                    message: format!("No account named {}", name),
+                }),
+        }
+    }
+}
+
+impl AccountConfig {
+    fn make(
+        user_config: AccountUserConfig,
+        name: String,
+        default_store_path: String,
+    ) -> Result<Self, MientConfigError> {
+        if matches!(&user_config.password_cmd, Some(cmd) if cmd.is_empty()) {
+            return Err(MientConfigError {
+                message: format!("Invalid password command for {}", name),
+            });
+        }
+        Ok(AccountConfig {
+            name,
             user: user_config.user,
             homeserver: user_config.homeserver,
-            password,
             device_id: user_config.device_id,
+            store_path: user_config.store_path.unwrap_or(default_store_path),
+            password_cmd: user_config.password_cmd,
+        })
+    }
+
+    /// Runs the password command if there is one, it is only needed when there is no session to
+    /// restore or when the server asks us to authenticate again.
+    pub fn password(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let password_cmd = match &self.password_cmd {
+            Some(cmd) => cmd,
+            None => return Ok(None),
         };
-        Ok(config)
+        let password = std::process::Command::new(&password_cmd[0])
+            .args(&password_cmd[1..])
+            .output()?
+            .stdout;
+        Ok(Some(String::from_utf8(password)?.trim().into()))
     }
 }
@@ -3,30 +3,76 @@ use std::{
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use matrix_sdk::ruma::{api::client::r0::uiaa::AuthData, UserId};
+use matrix_sdk::ruma::{
+    api::client::r0::uiaa::{AuthData, UiaaInfo},
+    UserId,
+};
 use serde_json::json;
 
-fn auth_data<'a>(user: &UserId, password: &str, session: Option<&'a str>) -> AuthData<'a> {
-    let mut auth_parameters = BTreeMap::new();
-    let identifier = json!({
-        "type": "m.id.user",
-        "user": user,
-    });
+/// Picks how to answer the server's user-interactive authentication: with our password if the
+/// server accepts it, or through the fallback web page of the next stage (e.g. single sign-on).
+pub async fn auth_data<'a>(
+    client: &matrix_sdk::Client,
+    user: &UserId,
+    password: Option<&str>,
+    uiaa_info: &'a UiaaInfo,
+) -> AuthData<'a> {
+    let session = uiaa_info.session.as_deref();
+    let accepts_password = uiaa_info
+        .flows
+        .iter()
+        .any(|flow| flow.stages.iter().any(|s| s == "m.login.password"));
+
+    if let (true, Some(password)) = (accepts_password, password) {
+        let mut auth_parameters = BTreeMap::new();
+        let identifier = json!({
+            "type": "m.id.user",
+            "user": user,
+        });
+
+        auth_parameters.insert("identifier".to_owned(), identifier);
+        auth_parameters.insert("password".to_owned(), password.to_owned().into());
 
-    auth_parameters.insert("identifier".to_owned(), identifier);
-    auth_parameters.insert("password".to_owned(), password.to_owned().into());
+        return AuthData::DirectRequest {
+            kind: "m.login.password",
+            auth_parameters,
+            session,
+        };
+    }
+
+    let stage = uiaa_info
+        .flows
+        .iter()
+        .flat_map(|flow| flow.stages.iter())
+        .find(|stage| !uiaa_info.completed.contains(stage))
+        .map(|stage| stage.as_str())
+        .unwrap_or("m.login.sso");
+    let mut fallback_url = client
+        .homeserver()
+        .await
+        .join(&format!("_matrix/client/r0/auth/{}/fallback/web", stage))
+        .expect("Couldn't build the authentication URL");
+    if let Some(session) = session {
+        fallback_url
+            .query_pairs_mut()
+            .append_pair("session", session);
+    }
+    println!("Authenticate in your browser, then press Enter:");
+    println!("{}", fallback_url);
+    let mut input = String::new();
+    std::io::stdin()
+        .read_line(&mut input)
+        .expect("error: unable to read user input");
 
-    AuthData::DirectRequest {
-        kind: "m.login.password",
-        auth_parameters,
-        session,
+    AuthData::FallbackAcknowledgement {
+        session: session.unwrap_or_default(),
     }
 }
 
-async fn bootstrap(client: &matrix_sdk::Client, user_id: UserId, password: &str) {
+async fn bootstrap(client: &matrix_sdk::Client, user_id: UserId, password: Option<&str>) {
     if let Err(e) = client.bootstrap_cross_signing(None).await {
         if let Some(response) = e.uiaa_response() {
-            let auth_data = auth_data(&user_id, &password, response.session.as_deref());
+            let auth_data = auth_data(client, &user_id, password, response).await;
             client
                 .bootstrap_cross_signing(Some(auth_data))
                 .await
@@ -39,7 +85,7 @@ async fn bootstrap(client: &matrix_sdk::Client, user_id: UserId, password: &str)
 
 pub async fn cross_sign(
     client: &matrix_sdk::Client,
-    password: &str,
+    password: Option<&str>,
 ) -> Result<(), matrix_sdk::Error> {
     client
         .sync_with_callback(matrix_sdk::SyncSettings::new(), |_| async move {
@@ -0,0 +1,45 @@
+use matrix_sdk::ruma::DeviceIdBox;
+
+use crate::config::AccountConfig;
+
+pub async fn list_devices(client: &matrix_sdk::Client) -> Result<(), Box<dyn std::error::Error>> {
+    let user_id = client.user_id().await.unwrap();
+    for device in client.get_user_devices(&user_id).await?.devices() {
+        println!(
+            "Device: {}, Trust: {:?}, Name: {}",
+            device.device_id(),
+            device.local_trust_state(),
+            device.display_name().as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+pub async fn delete_device(
+    client: &matrix_sdk::Client,
+    account_config: &AccountConfig,
+    device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if client
+        .device_id()
+        .await
+        .map_or(false, |d| d.as_str() == device)
+    {
+        return Err("This is the current device, log out instead".into());
+    }
+    let devices: [DeviceIdBox; 1] = [device.into()];
+    if let Err(e) = client.delete_devices(&devices, None).await {
+        if let Some(response) = e.uiaa_response() {
+            let user_id = client.user_id().await.unwrap();
+            let password = account_config.password()?;
+            let auth_data =
+                crate::cross_signing::auth_data(client, &user_id, password.as_deref(), response)
+                    .await;
+            client.delete_devices(&devices, Some(auth_data)).await?;
+        } else {
+            return Err(Box::new(e));
+        }
+    }
+    println!("Deleted {}", device);
+    Ok(())
+}
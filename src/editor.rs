@@ -0,0 +1,266 @@
+use termion::event::Key;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The text of an input line and the position of the cursor in it, kept by rooms while they aren't
+/// the current one.
+#[derive(Debug, Default)]
+pub struct Draft {
+    text: String,
+    // byte index, always on a grapheme boundary
+    cursor: usize,
+}
+
+/// Readline-like input line editor, with a kill ring and a history of the sent lines.
+#[derive(Debug, Default)]
+pub struct Editor {
+    draft: Draft,
+    kill_ring: Vec<String>,
+    // range of the text inserted by the last yank, and which kill it was
+    last_yank: Option<(usize, usize, usize)>,
+    history: Vec<String>,
+    history_index: Option<usize>,
+    // what was being typed before browsing the history
+    history_draft: String,
+    completion: Option<Completion>,
+}
+
+/// The candidates for the word being completed, the one inserted being at `index`.
+#[derive(Debug)]
+struct Completion {
+    start: usize,
+    candidates: Vec<String>,
+    index: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+impl Editor {
+    pub fn text(&self) -> &str {
+        &self.draft.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.draft.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.draft.text.is_empty()
+    }
+
+    pub fn set_text(&mut self, text: String) {
+        self.draft.cursor = text.len();
+        self.draft.text = text;
+        self.last_yank = None;
+        self.completion = None;
+    }
+
+    /// Empties the line and returns its content, remembering it in the history.
+    pub fn take(&mut self) -> String {
+        let text = std::mem::take(&mut self.draft.text);
+        self.draft.cursor = 0;
+        self.last_yank = None;
+        self.completion = None;
+        self.history_index = None;
+        if !text.is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        text
+    }
+
+    /// Swaps the line being edited with a room's draft.
+    pub fn swap_draft(&mut self, draft: &mut Draft) {
+        std::mem::swap(&mut self.draft, draft);
+        self.last_yank = None;
+        self.completion = None;
+        self.history_index = None;
+    }
+
+    pub fn is_browsing_history(&self) -> bool {
+        self.history_index.is_some()
+    }
+
+    pub fn history_previous(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(i) => i - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.history_draft = self.draft.text.clone();
+                self.history.len() - 1
+            }
+        };
+        self.set_text(self.history[index].clone());
+        self.history_index = Some(index);
+    }
+
+    pub fn history_next(&mut self) {
+        match self.history_index {
+            Some(i) if i + 1 < self.history.len() => {
+                self.set_text(self.history[i + 1].clone());
+                self.history_index = Some(i + 1);
+            }
+            Some(_) => {
+                let draft = std::mem::take(&mut self.history_draft);
+                self.set_text(draft);
+                self.history_index = None;
+            }
+            None => {}
+        }
+    }
+
+    fn previous_boundary(&self) -> usize {
+        self.draft.text[..self.draft.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.draft.text[self.draft.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.draft.cursor, |g| self.draft.cursor + g.len())
+    }
+
+    /// Start of the word before the cursor, words being delimited by `is_separator`.
+    fn word_start(&self, is_separator: impl Fn(char) -> bool) -> usize {
+        let before = &self.draft.text[..self.draft.cursor];
+        let end = before.trim_end_matches(|c| is_separator(c)).len();
+        before[..end]
+            .rfind(|c| is_separator(c))
+            .map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8())
+    }
+
+    fn word_end(&self, is_separator: impl Fn(char) -> bool) -> usize {
+        let after = &self.draft.text[self.draft.cursor..];
+        let start = after.len() - after.trim_start_matches(|c| is_separator(c)).len();
+        self.draft.cursor
+            + after[start..]
+                .find(|c| is_separator(c))
+                .map_or(after.len(), |i| start + i)
+    }
+
+    /// The whitespace delimited word before the cursor, and where it starts.
+    pub fn word_before_cursor(&self) -> (usize, &str) {
+        let before = &self.draft.text[..self.draft.cursor];
+        let start = before
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8());
+        (start, &before[start..])
+    }
+
+    pub fn is_completing(&self) -> bool {
+        self.completion.is_some()
+    }
+
+    /// Replaces what is between `start` and the cursor with the first candidate, the others can
+    /// then be cycled through with `cycle_completion`.
+    pub fn complete(&mut self, start: usize, candidates: Vec<String>) {
+        if candidates.is_empty() {
+            return;
+        }
+        self.draft
+            .text
+            .replace_range(start..self.draft.cursor, &candidates[0]);
+        self.draft.cursor = start + candidates[0].len();
+        self.completion = Some(Completion {
+            start,
+            candidates,
+            index: 0,
+        });
+    }
+
+    pub fn cycle_completion(&mut self, forward: bool) {
+        let completion = match &mut self.completion {
+            Some(c) => c,
+            None => return,
+        };
+        let count = completion.candidates.len();
+        let end = completion.start + completion.candidates[completion.index].len();
+        completion.index = if forward {
+            (completion.index + 1) % count
+        } else {
+            (completion.index + count - 1) % count
+        };
+        let candidate = &completion.candidates[completion.index];
+        self.draft
+            .text
+            .replace_range(completion.start..end, candidate);
+        self.draft.cursor = completion.start + candidate.len();
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        self.draft.text.insert_str(self.draft.cursor, text);
+        self.draft.cursor += text.len();
+    }
+
+    fn kill(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        let killed = self.draft.text.drain(start..end).collect();
+        self.kill_ring.push(killed);
+        self.draft.cursor = start;
+    }
+
+    fn yank(&mut self, ring_index: usize) {
+        let text = self.kill_ring[ring_index].clone();
+        let start = self.draft.cursor;
+        self.insert(&text);
+        self.last_yank = Some((start, self.draft.cursor, ring_index));
+    }
+
+    /// Returns false if the key isn't an editing key.
+    pub fn handle_key(&mut self, key: Key) -> bool {
+        let last_yank = self.last_yank.take();
+        self.completion = None;
+        match key {
+            Key::Char(c) => {
+                let mut buffer = [0; 4];
+                self.insert(c.encode_utf8(&mut buffer));
+            }
+            // Alt-Enter, since Enter sends
+            Key::Alt('\r') | Key::Alt('\n') => self.insert("\n"),
+            Key::Backspace | Key::Ctrl('h') => {
+                let start = self.previous_boundary();
+                self.draft.text.replace_range(start..self.draft.cursor, "");
+                self.draft.cursor = start;
+            }
+            Key::Ctrl('d') => {
+                let end = self.next_boundary();
+                self.draft.text.replace_range(self.draft.cursor..end, "");
+            }
+            Key::Left | Key::Ctrl('b') => self.draft.cursor = self.previous_boundary(),
+            Key::Right | Key::Ctrl('f') => self.draft.cursor = self.next_boundary(),
+            Key::Alt('b') => self.draft.cursor = self.word_start(|c| !is_word_char(c)),
+            Key::Alt('f') => self.draft.cursor = self.word_end(|c| !is_word_char(c)),
+            Key::Home | Key::Ctrl('a') => self.draft.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.draft.cursor = self.draft.text.len(),
+            Key::Ctrl('u') => self.kill(0, self.draft.cursor),
+            Key::Ctrl('k') => self.kill(self.draft.cursor, self.draft.text.len()),
+            Key::Ctrl('w') => self.kill(self.word_start(char::is_whitespace), self.draft.cursor),
+            Key::Alt('d') => self.kill(self.draft.cursor, self.word_end(|c| !is_word_char(c))),
+            Key::Ctrl('y') if !self.kill_ring.is_empty() => self.yank(self.kill_ring.len() - 1),
+            // replaces what was just yanked with the previous kill
+            Key::Alt('y') => {
+                if let Some((start, end, ring_index)) = last_yank {
+                    self.draft.text.replace_range(start..end, "");
+                    self.draft.cursor = start;
+                    let ring_index = ring_index
+                        .checked_sub(1)
+                        .unwrap_or(self.kill_ring.len() - 1);
+                    self.yank(ring_index);
+                }
+            }
+            Key::Ctrl('y') => {}
+            _ => {
+                self.last_yank = last_yank;
+                return false;
+            }
+        }
+        self.history_index = None;
+        true
+    }
+}
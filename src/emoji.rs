@@ -0,0 +1,197 @@
+use matrix_sdk::ruma::{EventId, RoomId};
+
+/// How many of the last used emoji the picker proposes first.
+const RECENT_COUNT: usize = 16;
+
+/// Shortcodes of the most common emoji, as used by other clients between colons (`:smile:`).
+pub const EMOJI: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("alien", "👽"),
+    ("angry", "😠"),
+    ("anguished", "😧"),
+    ("astonished", "😲"),
+    ("ballot_box_with_check", "☑️"),
+    ("beer", "🍺"),
+    ("beers", "🍻"),
+    ("bell", "🔔"),
+    ("blush", "😊"),
+    ("boom", "💥"),
+    ("bow", "🙇"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("bulb", "💡"),
+    ("cake", "🍰"),
+    ("calendar", "📆"),
+    ("cat", "🐱"),
+    ("champagne", "🍾"),
+    ("check", "✔️"),
+    ("clap", "👏"),
+    ("clinking_glasses", "🥂"),
+    ("cloud", "☁️"),
+    ("coffee", "☕"),
+    ("cold_sweat", "😰"),
+    ("confetti_ball", "🎊"),
+    ("confused", "😕"),
+    ("construction", "🚧"),
+    ("cool", "🆒"),
+    ("cry", "😢"),
+    ("crying_cat_face", "😿"),
+    ("dancer", "💃"),
+    ("disappointed", "😞"),
+    ("dizzy_face", "😵"),
+    ("dog", "🐶"),
+    ("eyes", "👀"),
+    ("face_palm", "🤦"),
+    ("facepalm", "🤦"),
+    ("fearful", "😨"),
+    ("fire", "🔥"),
+    ("flushed", "😳"),
+    ("frowning", "😦"),
+    ("gift", "🎁"),
+    ("grimacing", "😬"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("green_heart", "💚"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("heavy_check_mark", "✔️"),
+    ("heavy_minus_sign", "➖"),
+    ("heavy_plus_sign", "➕"),
+    ("hourglass", "⌛"),
+    ("hugs", "🤗"),
+    ("hushed", "😯"),
+    ("innocent", "😇"),
+    ("joy", "😂"),
+    ("kiss", "💋"),
+    ("kissing_heart", "😘"),
+    ("laughing", "😆"),
+    ("lock", "🔒"),
+    ("mag", "🔍"),
+    ("mask", "😷"),
+    ("medal", "🏅"),
+    ("money_mouth_face", "🤑"),
+    ("moon", "🌙"),
+    ("muscle", "💪"),
+    ("nerd_face", "🤓"),
+    ("neutral_face", "😐"),
+    ("no_entry", "⛔"),
+    ("no_mouth", "😶"),
+    ("ok", "🆗"),
+    ("ok_hand", "👌"),
+    ("open_mouth", "😮"),
+    ("package", "📦"),
+    ("partying_face", "🥳"),
+    ("pensive", "😔"),
+    ("persevere", "😣"),
+    ("pizza", "🍕"),
+    ("point_down", "👇"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("point_up", "☝️"),
+    ("poop", "💩"),
+    ("pray", "🙏"),
+    ("purple_heart", "💜"),
+    ("question", "❓"),
+    ("rage", "😡"),
+    ("raised_hands", "🙌"),
+    ("raising_hand", "🙋"),
+    ("relaxed", "☺️"),
+    ("relieved", "😌"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("rolling_eyes", "🙄"),
+    ("rose", "🌹"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("sleepy", "😪"),
+    ("slightly_frowning_face", "🙁"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("speak_no_evil", "🙊"),
+    ("star", "⭐"),
+    ("star_struck", "🤩"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("sun", "☀️"),
+    ("sunglasses", "😎"),
+    ("sweat", "😓"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("tired_face", "😫"),
+    ("tongue", "👅"),
+    ("trophy", "🏆"),
+    ("unamused", "😒"),
+    ("upside_down_face", "🙃"),
+    ("v", "✌️"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("weary", "😩"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("worried", "😟"),
+    ("x", "❌"),
+    ("yawning_face", "🥱"),
+    ("yellow_heart", "💛"),
+    ("yum", "😋"),
+    ("zany_face", "🤪"),
+    ("zap", "⚡"),
+    ("zipper_mouth_face", "🤐"),
+];
+
+/// The emoji picker used to react to a message, searchable by shortcode.
+#[derive(Debug)]
+pub struct EmojiPicker {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub query: String,
+    pub index: usize,
+}
+
+impl EmojiPicker {
+    pub fn new(room_id: RoomId, event_id: EventId) -> Self {
+        Self {
+            room_id,
+            event_id,
+            query: String::new(),
+            index: 0,
+        }
+    }
+
+    /// The emoji and their shortcodes matching the query, the recently used ones first.
+    pub fn candidates<'a>(&self, recent: &'a [String]) -> Vec<(&'a str, &'a str)> {
+        let recent = recent.iter().map(|emoji| {
+            let name = EMOJI
+                .iter()
+                .find(|(_, e)| e == emoji)
+                .map_or("", |(name, _)| *name);
+            (name, emoji.as_str())
+        });
+        let mut candidates: Vec<(&str, &str)> = Vec::new();
+        for (name, emoji) in recent.chain(EMOJI.iter().copied()) {
+            let matches = name.contains(self.query.as_str()) || emoji == self.query;
+            if matches && !candidates.iter().any(|(_, e)| *e == emoji) {
+                candidates.push((name, emoji));
+            }
+        }
+        candidates
+    }
+}
+
+/// Moves `emoji` to the front of the recently used ones.
+pub fn add_recent(recent: &mut Vec<String>, emoji: &str) {
+    recent.retain(|e| e != emoji);
+    recent.insert(0, emoji.to_owned());
+    recent.truncate(RECENT_COUNT);
+}
@@ -1,31 +1,44 @@
-use std::collections::{HashMap, HashSet};
-
+use matrix_sdk::ruma::events::reaction::{self, ReactionEventContent};
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
-use matrix_sdk::ruma::events::MessageEvent;
+use matrix_sdk::ruma::events::{AnyMessageEventContent, MessageEvent};
 use matrix_sdk::ruma::EventId;
 use matrix_sdk::ruma::RoomId;
 use matrix_sdk::ruma::UserId;
 use matrix_sdk::uuid::Uuid;
+use serde::Serialize;
 use termion::event::Key;
 
-use crate::state::Message;
+use crate::emoji::EmojiPicker;
 use crate::state::Room;
+use crate::state::SendState;
 use crate::state::State;
+use crate::utils::MessageKind;
 
 #[derive(Debug)]
 pub struct UserEvent;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum MatrixEvent {
     RoomName {
         id: RoomId,
         name: String,
     },
+    /// A room the user asked for, added to the list if it isn't in it yet, then selected
+    OpenRoom {
+        id: RoomId,
+        name: String,
+    },
     NewMessage {
         event: MessageEvent<MessageEventContent>,
+        /// The message that started the thread this one is in.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thread_id: Option<EventId>,
     },
     OldMessage {
         event: MessageEvent<MessageEventContent>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thread_id: Option<EventId>,
     },
     Notifications {
         id: RoomId,
@@ -36,6 +49,8 @@ pub enum MatrixEvent {
         prev_batch: String,
     },
     Reaction {
+        room_id: RoomId,
+        reaction_id: EventId,
         event_id: EventId,
         user_id: UserId,
         emoji: String,
@@ -44,8 +59,70 @@ pub enum MatrixEvent {
         room_id: RoomId,
         redacted_id: EventId,
     },
+    /// A state event shown in the timeline
+    StateChange {
+        room_id: RoomId,
+        change: crate::state::StateChange,
+        /// Whether it comes from the history rather than the sync
+        old: bool,
+    },
+    /// From an `m.room.member` event, or the members of the room fetched at startup
+    Member {
+        room_id: RoomId,
+        user_id: UserId,
+        name: Option<String>,
+        /// Whether they are joined or invited
+        present: bool,
+        /// Whether it comes from the history, so their name may have changed since
+        old: bool,
+    },
+    RoomLeft {
+        id: RoomId,
+    },
+    MessageSent {
+        room_id: RoomId,
+        txn_id: String,
+        event_id: EventId,
+    },
+    MessageFailed {
+        room_id: RoomId,
+        txn_id: String,
+        error: String,
+    },
+    /// Feedback for the status line
+    Status {
+        message: String,
+    },
 }
 
+impl MatrixEvent {
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            MatrixEvent::RoomName { id, .. } => Some(id),
+            MatrixEvent::OpenRoom { id, .. } => Some(id),
+            MatrixEvent::NewMessage { event, .. } => Some(&event.room_id),
+            MatrixEvent::OldMessage { event, .. } => Some(&event.room_id),
+            MatrixEvent::Notifications { id, .. } => Some(id),
+            MatrixEvent::PrevBatch { id, .. } => Some(id),
+            MatrixEvent::Reaction { room_id, .. } => Some(room_id),
+            MatrixEvent::Redaction { room_id, .. } => Some(room_id),
+            MatrixEvent::StateChange { room_id, .. } => Some(room_id),
+            MatrixEvent::Member { room_id, .. } => Some(room_id),
+            MatrixEvent::RoomLeft { id } => Some(id),
+            MatrixEvent::MessageSent { room_id, .. } => Some(room_id),
+            MatrixEvent::MessageFailed { room_id, .. } => Some(room_id),
+            MatrixEvent::Status { .. } => None,
+        }
+    }
+}
+
+pub const EXTERNAL_EDITOR_KEY: Key = Key::Alt('e');
+pub const EDIT_KEY: Key = Key::Alt('m');
+pub const REACT_KEY: Key = Key::Alt('r');
+pub const RETRY_KEY: Key = Key::Alt('s');
+pub const THREAD_KEY: Key = Key::Alt('t');
+pub const EXPAND_KEY: Key = Key::Alt('c');
+
 #[derive(Debug)]
 pub enum MientEvent {
     Keyboard(Key),
@@ -62,54 +139,46 @@ fn handle_keyboard_event(
         Key::Char('\n') => {
             if let Some(room) = state.current_room() {
                 crate::matrix::send_read_receipt_current_room(client.clone(), room);
-                if state.input.is_empty() {
-                    return true;
+            }
+            if state.input.is_empty() {
+                return true;
+            }
+            // keep what was typed rather than losing it
+            if state.current_room().is_none() && crate::commands::needs_room(state.input.text()) {
+                state.status = String::from("Not in a room");
+                return true;
+            }
+            let text = state.input.take();
+            state.status.clear();
+            if let Some(event_id) = state.editing.take() {
+                crate::matrix::send_edit(state, client, tx, &text, &event_id);
+                return true;
+            }
+            match text.strip_prefix('/') {
+                // a leading // sends the message with a single /
+                Some(command) if !command.starts_with('/') => {
+                    return crate::commands::run(command, state, client, tx)
+                }
+                Some(text) => {
+                    let markdown = state.config.markdown;
+                    crate::matrix::send_message(state, text, MessageKind::Text, markdown)
+                }
+                None => {
+                    let markdown = state.config.markdown;
+                    crate::matrix::send_message(state, &text, MessageKind::Text, markdown)
                 }
-                use matrix_sdk::ruma::events::room::message;
-                let id = room.id.clone();
-                let selected_message = room
-                    .message_list
-                    .messages
-                    .get(room.message_list.current_index)
-                    .cloned();
-
-                let text: String = state.input.drain(..).collect();
-                let mut text_content = message::TextMessageEventContent::plain(text.clone());
-
-                let mut relates_to = None;
-                if let Some(msg) = selected_message {
-                    let reply = message::Relation::Reply {
-                        in_reply_to: message::InReplyTo::new(msg.event.event_id.clone()),
-                    };
-                    relates_to = Some(reply);
-                    text_content.body = crate::utils::format_reply_content(
-                        &msg.event.content,
-                        &msg.event.sender,
-                        &text,
-                    );
-                };
-
-                let content =
-                    matrix_sdk::ruma::events::room::message::MessageType::Text(text_content);
-                let mut content = message::MessageEventContent::new(content);
-                content.relates_to = relates_to;
-                let message =
-                    matrix_sdk::ruma::events::AnyMessageEventContent::RoomMessage(content);
-                let client = client.clone();
-                tokio::task::spawn(async move { client.room_send(&id, message, None).await });
-                // TODO txn id for local echo
             }
         }
-        Key::Char(c) => state.input.push(c),
-        Key::Backspace => {
-            state.input.pop();
-        }
-        Key::Ctrl('u') => state.input.clear(),
         Key::Ctrl('p') => state.change_current_room(-1),
         Key::Ctrl('n') => state.change_current_room(1),
-        Key::Up | Key::Home => {
+        Key::Alt('p') => state.input.history_previous(),
+        Key::Alt('n') => state.input.history_next(),
+        Key::Up if state.input.is_browsing_history() => state.input.history_previous(),
+        Key::Down if state.input.is_browsing_history() => state.input.history_next(),
+        Key::Up | Key::Alt('<') => {
             if let Some(mut room) = state.current_room_mut() {
-                if room.message_list.current_index == 0 {
+                // threads only have the replies that came with the timeline
+                if room.open_thread.is_none() && room.message_list.current_index == 0 {
                     crate::matrix::fetch_old_messages(
                         room.id.clone(),
                         &mut room,
@@ -119,7 +188,7 @@ fn handle_keyboard_event(
                 } else {
                     let position = match key {
                         Key::Up => crate::state::ListPosition::Relative(-1),
-                        Key::Home => crate::state::ListPosition::First,
+                        Key::Alt('<') => crate::state::ListPosition::First,
                         _ => unreachable!(),
                     };
                     state.change_current_message(position);
@@ -127,18 +196,25 @@ fn handle_keyboard_event(
             }
         }
         Key::Down => state.change_current_message(crate::state::ListPosition::Relative(1)),
-        Key::End => state.change_current_message(crate::state::ListPosition::Last),
+        Key::Alt('>') => state.change_current_message(crate::state::ListPosition::Last),
         Key::Delete => {
             let room = match state.current_room() {
                 Some(r) => r,
                 None => return true,
             };
-            let selected_message = room
-                .message_list
-                .messages
-                .get(room.message_list.current_index)
-                .cloned();
+            let selected_message = room.selected_message().cloned();
             if let Some(msg) = selected_message {
+                // grouped runs stand for several events, none of them is ours to redact here
+                if msg.is_state_change() {
+                    return true;
+                }
+                // not on the server yet, so it's enough to stop sending it
+                if !msg.is_on_server() {
+                    if let Some(echo) = &msg.local_echo {
+                        cancel_message(state, &msg.event.room_id, &echo.txn_id);
+                    }
+                    return true;
+                }
                 let txn_id = Uuid::new_v4().to_string();
                 let client = client.clone();
                 let room_id = msg.event.room_id.clone();
@@ -153,11 +229,210 @@ fn handle_keyboard_event(
             }
         }
         Key::Esc => return false,
-        _ => {}
+        EXTERNAL_EDITOR_KEY => state.external_editor = true,
+        EDIT_KEY => toggle_editing(state),
+        THREAD_KEY => toggle_thread(state),
+        EXPAND_KEY => {
+            if let Some(room) = state.current_room_mut() {
+                let message_list = room.selected_list_mut();
+                let index = message_list.current_index;
+                if let Some(message) = message_list.messages.get_mut(index) {
+                    message.expanded = !message.expanded;
+                }
+            }
+        }
+        RETRY_KEY => retry_message(state),
+        REACT_KEY => {
+            let room = match state.current_room() {
+                Some(r) => r,
+                None => return true,
+            };
+            if let Some(msg) = room.selected_message() {
+                if !msg.is_on_server() {
+                    state.status = String::from("The message isn't sent yet");
+                    return true;
+                }
+                if msg.is_state_change() {
+                    return true;
+                }
+                state.emoji_picker = Some(EmojiPicker::new(
+                    room.id.clone(),
+                    msg.event.event_id.clone(),
+                ));
+            }
+        }
+        key => {
+            state.input.handle_key(key);
+        }
     };
     true
 }
 
+/// Loads the selected message in the input to edit it, or goes back to writing a new message.
+fn toggle_editing(state: &mut State) {
+    if state.editing.take().is_some() {
+        state.input.set_text(String::new());
+        state.status.clear();
+        return;
+    }
+    let room = match state.current_room() {
+        Some(r) => r,
+        None => return,
+    };
+    let message = match room.selected_message() {
+        Some(m) => m,
+        None => return,
+    };
+    if message.event.sender != state.user_id || message.is_state_change() {
+        state.status = String::from("Only our own messages can be edited");
+        return;
+    }
+    if !message.is_on_server() {
+        state.status = String::from("The message isn't sent yet");
+        return;
+    }
+    let text = crate::utils::format_message_body(message.content()).to_owned();
+    state.editing = Some(message.event.event_id.clone());
+    state.input.set_text(text);
+    state.status = String::from("Editing, Enter to send the edit, Alt-m to cancel");
+}
+
+/// Opens the thread of the selected message next to the timeline, or closes the open one.
+fn toggle_thread(state: &mut State) {
+    state.editing = None;
+    let room = match state.current_room_mut() {
+        Some(r) => r,
+        None => return,
+    };
+    let status = if room.open_thread.take().is_some() {
+        ""
+    } else {
+        match room.message_list.selected() {
+            Some(msg) if msg.is_state_change() => return,
+            Some(msg) if msg.is_on_server() => {
+                room.open_thread = Some(msg.event.event_id.clone());
+                // new replies are sent to the thread
+                let thread = room.selected_list_mut();
+                thread.current_index = thread.messages.len();
+                "Thread, messages are sent to it, Alt-t to go back to the room"
+            }
+            Some(_) => "The message isn't sent yet",
+            None => return,
+        }
+    };
+    state.status = String::from(status);
+}
+
+/// Sends the selected message again if it failed.
+fn retry_message(state: &mut State) {
+    let room = match state.current_room_mut() {
+        Some(r) => r,
+        None => return,
+    };
+    let message_list = room.selected_list_mut();
+    let echo = match message_list
+        .messages
+        .get_mut(message_list.current_index)
+        .and_then(|msg| msg.local_echo.as_mut())
+    {
+        Some(echo) if matches!(echo.state, SendState::Failed(_)) => echo,
+        _ => return,
+    };
+    echo.state = SendState::Sending;
+    let txn_id = echo.txn_id.clone();
+    state.send_queue.retry(Some(&txn_id));
+}
+
+/// Gives up on sending a message.
+fn cancel_message(state: &mut State, room_id: &RoomId, txn_id: &str) {
+    state.send_queue.remove(txn_id);
+    if let Some(room) = state.get_room_mut(room_id) {
+        room.remove_local_echo(txn_id);
+    }
+}
+
+/// Reacts with the emoji, or removes our reaction if we already had reacted with it.
+fn react(
+    state: &mut State,
+    client: &matrix_sdk::Client,
+    tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+    room_id: RoomId,
+    event_id: EventId,
+    emoji: String,
+) {
+    let client = client.clone();
+    let our_reaction = state
+        .get_room(&room_id)
+        .and_then(|room| room.reactions.find(&event_id, &state.user_id, &emoji))
+        .cloned();
+    match our_reaction {
+        Some(reaction_id) => crate::commands::spawn_command(tx, async move {
+            use matrix_sdk::ruma::api::client::r0::redact::redact_event::Request;
+            let txn_id = Uuid::new_v4().to_string();
+            let request = Request::new(&room_id, &reaction_id, &txn_id);
+            client.send(request, None).await.map(|_| ())
+        }),
+        None => {
+            crate::emoji::add_recent(&mut state.recent_emoji, &emoji);
+            let content = AnyMessageEventContent::Reaction(ReactionEventContent::new(
+                reaction::Relation::new(event_id, emoji),
+            ));
+            crate::commands::spawn_command(tx, async move {
+                client.room_send(&room_id, content, None).await.map(|_| ())
+            });
+        }
+    }
+}
+
+/// While the emoji picker is open, keys search and select emoji.
+fn handle_emoji_picker_key(
+    key: Key,
+    state: &mut State,
+    client: &matrix_sdk::Client,
+    tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+) {
+    let picker = match &mut state.emoji_picker {
+        Some(p) => p,
+        None => return,
+    };
+    let count = picker.candidates(&state.recent_emoji).len();
+    match key {
+        Key::Esc | REACT_KEY => state.emoji_picker = None,
+        Key::Char('\n') => {
+            let emoji = picker
+                .candidates(&state.recent_emoji)
+                .get(picker.index)
+                .map(|(_, emoji)| emoji.to_string());
+            let picker = state.emoji_picker.take().unwrap();
+            if let Some(emoji) = emoji {
+                react(state, client, tx, picker.room_id, picker.event_id, emoji);
+            }
+        }
+        Key::Up | Key::BackTab | Key::Ctrl('p') => {
+            picker.index = picker
+                .index
+                .checked_sub(1)
+                .unwrap_or(count.saturating_sub(1))
+        }
+        Key::Down | Key::Char('\t') | Key::Ctrl('n') => {
+            picker.index = if picker.index + 1 < count {
+                picker.index + 1
+            } else {
+                0
+            }
+        }
+        Key::Backspace => {
+            picker.query.pop();
+            picker.index = 0;
+        }
+        Key::Char(c) => {
+            picker.query.push(c);
+            picker.index = 0;
+        }
+        _ => {}
+    }
+}
+
 pub async fn handle_mient_event(
     event: MientEvent,
     state: &mut State,
@@ -165,31 +440,51 @@ pub async fn handle_mient_event(
     tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
 ) -> bool {
     match event {
+        MientEvent::Keyboard(key) if state.emoji_picker.is_some() => {
+            handle_emoji_picker_key(key, state, client, tx);
+            true
+        }
+        MientEvent::Keyboard(Key::Char('\t')) => {
+            crate::completion::complete(state, client, true);
+            true
+        }
+        MientEvent::Keyboard(Key::BackTab) => {
+            crate::completion::complete(state, client, false);
+            true
+        }
         MientEvent::Keyboard(key) => handle_keyboard_event(key, state, client, &tx),
         MientEvent::WindowChange => true,
     }
 }
 
+fn add_room(state: &mut State, id: RoomId, name: String) {
+    let mut room = Room::new(name, id, 0, None);
+    room.show_state_changes = state.config.shows_state_changes(&room.id, None);
+    state.rooms.push(room);
+}
+
 pub async fn handle_matrix_event(event: MatrixEvent, state: &mut State) {
     match event {
         MatrixEvent::RoomName { id, name } => match state.get_room_mut(&id) {
             Some(room) => room.name = name,
-            None => state.rooms.push(Room::new(name, id, 0, None)),
+            None => add_room(state, id, name),
         },
-        MatrixEvent::NewMessage { event } => {
+        MatrixEvent::OpenRoom { id, name } => {
+            if state.get_room_mut(&id).is_none() {
+                add_room(state, id.clone(), name);
+            }
+            if let Some(index) = state.rooms.iter().position(|r| r.id == id) {
+                state.set_current_room(index);
+            }
+        }
+        MatrixEvent::NewMessage { event, thread_id } => {
             if let Some(room) = state.get_room_mut(&event.room_id) {
-                room.message_list.push_new(Message {
-                    redacted: false,
-                    event,
-                })
+                room.push_new(event, thread_id)
             }
         }
-        MatrixEvent::OldMessage { event } => {
+        MatrixEvent::OldMessage { event, thread_id } => {
             if let Some(room) = state.get_room_mut(&event.room_id) {
-                room.message_list.push_old(Message {
-                    redacted: false,
-                    event,
-                })
+                room.push_old(event, thread_id)
             }
         }
         MatrixEvent::Notifications { id, count } => {
@@ -203,28 +498,88 @@ pub async fn handle_matrix_event(event: MatrixEvent, state: &mut State) {
                 .map(|room| room.prev_batch = Some(prev_batch));
         }
         MatrixEvent::Reaction {
+            room_id,
+            reaction_id,
             event_id,
             user_id,
             emoji,
         } => {
-            state
-                .reactions
-                .entry(event_id)
-                .or_insert_with(HashMap::new)
-                .entry(emoji)
-                .or_insert_with(HashSet::new)
-                .insert(user_id);
+            if let Some(room) = state.get_room_mut(&room_id) {
+                room.add_reaction(reaction_id, event_id, user_id, emoji);
+            }
+        }
+        MatrixEvent::StateChange {
+            room_id,
+            change,
+            old,
+        } => {
+            let group = state.config.group_membership_changes;
+            if let Some(room) = state.get_room_mut(&room_id) {
+                room.push_state_change(change, old, group);
+            }
+        }
+        MatrixEvent::Member {
+            room_id,
+            user_id,
+            name,
+            present,
+            old,
+        } => {
+            if let Some(room) = state.get_room_mut(&room_id) {
+                if old {
+                    room.members.update_if_unknown(user_id, name, present);
+                } else {
+                    room.members.update(user_id, name, present);
+                }
+            }
+        }
+        MatrixEvent::RoomLeft { id } => {
+            state.rooms.retain(|room| room.id != id);
+            state.current_room_index = state
+                .current_room_index
+                .min(state.rooms.len().saturating_sub(1));
+        }
+        MatrixEvent::Status { message } => state.status = message,
+        MatrixEvent::MessageSent {
+            room_id,
+            txn_id,
+            event_id,
+        } => {
+            state.send_queue.remove(&txn_id);
+            // it may already have come back from sync
+            let message = state
+                .get_room_mut(&room_id)
+                .and_then(|room| room.local_echo_mut(&txn_id));
+            if let Some(message) = message {
+                message.event.event_id = event_id;
+                if let Some(echo) = &mut message.local_echo {
+                    echo.state = SendState::Sent;
+                }
+            }
+        }
+        MatrixEvent::MessageFailed {
+            room_id,
+            txn_id,
+            error,
+        } => {
+            let message = state
+                .get_room_mut(&room_id)
+                .and_then(|room| room.local_echo_mut(&txn_id));
+            if let Some(echo) = message.and_then(|m| m.local_echo.as_mut()) {
+                echo.state = SendState::Failed(error);
+            }
         }
         MatrixEvent::Redaction {
             room_id,
             redacted_id,
         } => {
             if let Some(room) = state.rooms.iter_mut().find(|r| r.id == room_id) {
-                room.message_list
-                    .messages
-                    .iter_mut()
-                    .rev()
-                    .find(|msg| msg.event.event_id == redacted_id)
+                if room.reactions.remove(&redacted_id) {
+                    return;
+                }
+                // the reactions to a redacted message go with it
+                room.reactions.remove_message(&redacted_id);
+                room.find_message_mut(&redacted_id)
                     .map(|msg| msg.redacted = true);
             }
         }
@@ -0,0 +1,208 @@
+use std::io::prelude::Write;
+use std::str::FromStr;
+
+use chrono::TimeZone;
+use matrix_sdk::ruma::{
+    events::{
+        room::message::{MessageEventContent, MessageType},
+        AnyMessageEvent, AnyRoomEvent, AnyStateEvent, MessageEvent,
+    },
+    serde::Raw,
+};
+
+pub enum ExportFormat {
+    Json,
+    Text,
+    Html,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "text" => Ok(ExportFormat::Text),
+            "html" => Ok(ExportFormat::Html),
+            _ => Err(format!("Unknown format {}, must be json, text or html", s)),
+        }
+    }
+}
+
+fn format_time(timestamp_ms: u64) -> String {
+    match chrono::Local
+        .timestamp_millis_opt(timestamp_ms as i64)
+        .single()
+    {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        // out of chrono's range, shouldn't come from a server
+        None => timestamp_ms.to_string(),
+    }
+}
+
+fn timestamp_ms(event: &AnyRoomEvent) -> u64 {
+    event.origin_server_ts().0.into()
+}
+
+/// Paginates backwards through the whole room, or until `since_ms`, and returns its events in
+/// chronological order.
+async fn fetch_history(
+    client: &matrix_sdk::Client,
+    room_id: &matrix_sdk::ruma::RoomId,
+    since_ms: Option<u64>,
+) -> Result<Vec<Raw<AnyRoomEvent>>, Box<dyn std::error::Error>> {
+    crate::matrix::sync_once(client).await?;
+    let mut from = client
+        .sync_token()
+        .await
+        .ok_or("No sync token to paginate from")?;
+    let mut events = Vec::new();
+    loop {
+        let response = crate::matrix::messages_backward(client, room_id, &from, 100)
+            .await?
+            .ok_or_else(|| format!("Unknown room {}", room_id))?;
+        if response.chunk.is_empty() {
+            break;
+        }
+        let mut done = false;
+        for raw in response.chunk {
+            let event = match raw.deserialize() {
+                Ok(e) => e,
+                Err(err) => {
+                    crate::log::error(&err.to_string());
+                    continue;
+                }
+            };
+            if since_ms.map_or(false, |since| timestamp_ms(&event) < since) {
+                done = true;
+                break;
+            }
+            events.push(raw);
+            if let AnyRoomEvent::State(AnyStateEvent::RoomCreate(_)) = event {
+                done = true;
+            }
+        }
+        eprint!("\rFetched {} events", events.len());
+        match response.end {
+            Some(end) if !done && end != from => from = end,
+            _ => break,
+        }
+    }
+    eprintln!();
+    events.reverse();
+    Ok(events)
+}
+
+fn room_messages(
+    events: &[Raw<AnyRoomEvent>],
+) -> impl Iterator<Item = MessageEvent<MessageEventContent>> + '_ {
+    events.iter().filter_map(|raw| match raw.deserialize() {
+        Ok(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(event))) => Some(event),
+        _ => None,
+    })
+}
+
+fn write_text(
+    out: &mut dyn Write,
+    events: &[Raw<AnyRoomEvent>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for event in room_messages(events) {
+        let time = format_time(event.origin_server_ts.0.into());
+        let body = crate::utils::format_message_body(&event.content);
+        let mut lines = body.lines();
+        let first = lines.next().unwrap_or("");
+        if let MessageType::Emote(_) = event.content.msgtype {
+            writeln!(out, "[{}] * {} {}", time, event.sender, first)?;
+        } else {
+            writeln!(out, "[{}] <{}> {}", time, event.sender, first)?;
+        }
+        for line in lines {
+            writeln!(out, "    {}", line)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_html(
+    out: &mut dyn Write,
+    room_name: &str,
+    events: &[Raw<AnyRoomEvent>],
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::utils::escape_html;
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>{}</title>", escape_html(room_name))?;
+    writeln!(
+        out,
+        "<style>
+body {{ font-family: sans-serif; max-width: 60em; margin: auto; }}
+.time {{ color: grey; font-size: small; }}
+.sender {{ font-weight: bold; }}
+.body {{ white-space: pre-wrap; margin: 0 0 0.5em 1em; }}
+.emote {{ font-style: italic; }}
+</style>"
+    )?;
+    writeln!(out, "</head>\n<body>")?;
+    writeln!(out, "<h1>{}</h1>", escape_html(room_name))?;
+    for event in room_messages(events) {
+        let class = match event.content.msgtype {
+            MessageType::Emote(_) => "body emote",
+            _ => "body",
+        };
+        writeln!(
+            out,
+            "<div class=\"message\"><span class=\"time\">{}</span> <span class=\"sender\">{}</span><div class=\"{}\">{}</div></div>",
+            format_time(event.origin_server_ts.0.into()),
+            escape_html(event.sender.as_str()),
+            class,
+            escape_html(crate::utils::format_message_body(&event.content)),
+        )?;
+    }
+    writeln!(out, "</body>\n</html>")?;
+    Ok(())
+}
+
+/// Writes the history of a room to `output`, or stdout.
+pub async fn export(
+    client: &matrix_sdk::Client,
+    room: &str,
+    format: ExportFormat,
+    since: Option<&str>,
+    output: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let since_ms = match since {
+        Some(date) => {
+            let date = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+            let midnight = date.and_hms_opt(0, 0, 0).ok_or("Invalid date")?;
+            let since = chrono::Local
+                .from_local_datetime(&midnight)
+                .earliest()
+                .ok_or("Invalid date")?;
+            Some(since.timestamp_millis() as u64)
+        }
+        None => None,
+    };
+    let room_id = crate::send::resolve_room(client, room).await?;
+    let events = fetch_history(client, &room_id, since_ms).await?;
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    };
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &events)?;
+            writeln!(out)?;
+        }
+        ExportFormat::Text => write_text(&mut out, &events)?,
+        ExportFormat::Html => {
+            let room_name = match client.get_room(&room_id) {
+                Some(room) => room.display_name().await?,
+                None => room_id.to_string(),
+            };
+            write_html(&mut out, &room_name, &events)?
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
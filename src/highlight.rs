@@ -0,0 +1,108 @@
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use tui::style::{Color, Style};
+use tui::text::{Span, Spans};
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+pub struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    /// `theme` is either the name of a theme bundled with syntect or the path of a `.tmTheme`.
+    pub fn new(theme: &str) -> Result<Self, String> {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = match themes.themes.remove(theme) {
+            Some(t) => t,
+            None => ThemeSet::get_theme(theme)
+                .map_err(|e| format!("Couldn't load the syntax theme {}: {}", theme, e))?,
+        };
+        Ok(Self {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+        })
+    }
+
+    fn highlight_line(&self, highlighter: &mut HighlightLines, line: &str) -> Vec<Span<'static>> {
+        // tabs would mess up the frame
+        let mut line = line.trim_end_matches('\n').replace('\t', "    ");
+        // the syntaxes close scopes like line comments on the newline, it is dropped afterwards
+        line.push('\n');
+        match highlighter.highlight_line(&line, &self.syntax_set) {
+            Ok(ranges) => ranges
+                .into_iter()
+                .map(|(style, text)| (style, text.trim_end_matches('\n')))
+                .filter(|(_, text)| !text.is_empty())
+                .map(|(style, text)| {
+                    let color = style.foreground;
+                    Span::styled(
+                        text.to_owned(),
+                        Style::default().fg(Color::Rgb(color.r, color.g, color.b)),
+                    )
+                })
+                .collect(),
+            Err(_) => vec![Span::raw(line.trim_end_matches('\n').to_owned())],
+        }
+    }
+
+    /// Highlights a block of code and frames it to set it apart from the prose around it. The
+    /// language is guessed from the first line when it isn't given.
+    pub fn code_block(&self, code: &str, language: Option<&str>) -> Vec<Spans<'static>> {
+        let syntax = language
+            .and_then(|l| self.syntax_set.find_syntax_by_token(l))
+            .or_else(|| self.syntax_set.find_syntax_by_first_line(code))
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let mut highlighter = HighlightLines::new(syntax, &self.theme);
+        let frame_style = Style::default().fg(Color::DarkGray);
+
+        let mut lines = Vec::new();
+        let title = match language {
+            Some(language) => format!("┌─ {}", language),
+            None => String::from("┌─"),
+        };
+        lines.push(Spans::from(Span::styled(title, frame_style)));
+        for line in LinesWithEndings::from(code.trim_end_matches('\n')) {
+            let mut spans = vec![Span::styled("│ ", frame_style)];
+            spans.extend(self.highlight_line(&mut highlighter, line));
+            lines.push(Spans::from(spans));
+        }
+        lines.push(Spans::from(Span::styled("└─", frame_style)));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style_of(line: &Spans, text: &str) -> Style {
+        line.0
+            .iter()
+            .find(|span| span.content.contains(text))
+            .unwrap_or_else(|| panic!("no span with {}", text))
+            .style
+    }
+
+    #[test]
+    fn closes_line_comments() {
+        let highlighter = Highlighter::new(DEFAULT_THEME).unwrap();
+        let lines = highlighter.code_block("# comment\nvalue = 1\n", Some("python"));
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].0.iter().all(|span| !span.content.contains('\n')));
+        assert_ne!(style_of(&lines[1], "comment"), style_of(&lines[2], "value"));
+    }
+
+    #[test]
+    fn keeps_block_comments_open() {
+        let highlighter = Highlighter::new(DEFAULT_THEME).unwrap();
+        let code = "/* first\nsecond */\nlet value = 1;";
+        let lines = highlighter.code_block(code, Some("rust"));
+        let comment = style_of(&lines[1], "first");
+        assert_eq!(style_of(&lines[2], "second"), comment);
+        assert_ne!(style_of(&lines[3], "let"), comment);
+    }
+}
@@ -0,0 +1,581 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::ruma::UserId;
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+
+use crate::highlight::Highlighter;
+use crate::state::Members;
+
+/// Tags that start on a new line and are followed by one.
+const BLOCK_TAGS: &[&str] = &[
+    "blockquote",
+    "details",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "summary",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// Tags that are never closed.
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+enum Token<'a> {
+    Text(&'a str),
+    Start {
+        name: String,
+        attributes: Vec<(String, String)>,
+    },
+    End {
+        name: String,
+    },
+}
+
+/// Splits HTML in text and tags, comments are dropped. Doesn't try to validate anything, the
+/// renderer copes with unbalanced tags.
+fn tokenize(html: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment
+                .find("-->")
+                .map_or("", |end| &comment[end + "-->".len()..]);
+            continue;
+        }
+        if rest.starts_with('<') {
+            if let Some(end) = tag_end(rest) {
+                tokens.extend(parse_tag(&rest[1..end]));
+                rest = &rest[end + 1..];
+                continue;
+            }
+            // a stray <
+            tokens.push(Token::Text(&rest[..1]));
+            rest = &rest[1..];
+            continue;
+        }
+        let end = rest.find('<').unwrap_or(rest.len());
+        tokens.push(Token::Text(&rest[..end]));
+        rest = &rest[end..];
+    }
+    tokens
+}
+
+/// Index of the `>` closing the tag at the start of `html`, ignoring the ones in quoted values.
+fn tag_end(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in html.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            (None, '<') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_tag(tag: &str) -> Option<Token> {
+    if let Some(name) = tag.strip_prefix('/') {
+        return Some(Token::End {
+            name: name.trim().to_lowercase(),
+        });
+    }
+    let tag = tag.trim_end_matches('/');
+    let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+    let name = tag[..name_end].to_lowercase();
+    if name.is_empty() || name.starts_with('!') {
+        return None;
+    }
+
+    let mut attributes = Vec::new();
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_lowercase();
+        rest = rest[key_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after_equal) = rest.strip_prefix('=') {
+            rest = after_equal.trim_start();
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'');
+            let (raw_value, after_value) = match quote {
+                Some(quote) => {
+                    let end = rest[1..].find(quote).map_or(rest.len(), |i| i + 1);
+                    (&rest[1..end], rest.get(end + 1..).unwrap_or(""))
+                }
+                None => {
+                    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            value = decode_entities(raw_value);
+            rest = after_value.trim_start();
+        }
+        attributes.push((key, value));
+    }
+    Some(Token::Start { name, attributes })
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(std::char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// `#rrggbb`, as used by `data-mx-color` and `color`.
+fn parse_color(color: &str) -> Option<Color> {
+    let hex = color.strip_prefix('#').filter(|h| h.len() == 6)?;
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some(Color::Rgb(component(0)?, component(2)?, component(4)?))
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+struct Link {
+    href: String,
+    text: String,
+    // links to users are rendered as pills
+    user_id: Option<UserId>,
+}
+
+struct CodeBlock {
+    language: Option<String>,
+    code: String,
+}
+
+struct Renderer<'a> {
+    members: Option<&'a Members>,
+    highlighter: &'a Highlighter,
+    lines: Vec<Spans<'static>>,
+    line: Vec<Span<'static>>,
+    // open tags with the style they apply
+    styles: Vec<(String, Style)>,
+    // None for unordered lists, the number of the next item otherwise
+    lists: Vec<Option<u64>>,
+    quote_depth: usize,
+    // the content of a `pre` is highlighted once it is complete
+    code_block: Option<CodeBlock>,
+    reply_depth: usize,
+    link: Option<Link>,
+}
+
+impl<'a> Renderer<'a> {
+    fn style(&self) -> Style {
+        self.styles
+            .iter()
+            .fold(Style::default(), |style, (_, s)| style.patch(*s))
+    }
+
+    fn break_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        self.lines.push(Spans::from(line));
+    }
+
+    fn new_line(&mut self) {
+        if !self.line.is_empty() {
+            self.break_line();
+        }
+    }
+
+    fn push(&mut self, text: String, style: Style) {
+        if self.line.is_empty() && self.quote_depth > 0 {
+            self.line.push(Span::styled(
+                "│ ".repeat(self.quote_depth),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        self.line.push(Span::styled(text, style));
+    }
+
+    fn line_is_blank(&self) -> bool {
+        // without the quote prefix
+        self.line
+            .iter()
+            .skip((self.quote_depth > 0) as usize)
+            .all(|s| s.content.trim().is_empty())
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.reply_depth > 0 {
+            return;
+        }
+        let text = decode_entities(text);
+        if let Some(code_block) = &mut self.code_block {
+            code_block.code.push_str(&text);
+            return;
+        }
+
+        let mut collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.starts_with(char::is_whitespace) && !self.line_is_blank() {
+            collapsed.insert(0, ' ');
+        }
+        if text.ends_with(char::is_whitespace) && !collapsed.is_empty() {
+            collapsed.push(' ');
+        }
+        if collapsed.is_empty() {
+            return;
+        }
+        if let Some(link) = &mut self.link {
+            link.text.push_str(&collapsed);
+            if link.user_id.is_some() {
+                return;
+            }
+        }
+        self.push(collapsed, self.style());
+    }
+
+    fn start(&mut self, name: String, attributes: Vec<(String, String)>) {
+        if name == "mx-reply" {
+            self.reply_depth += 1;
+            return;
+        }
+        if self.reply_depth > 0 {
+            return;
+        }
+        if BLOCK_TAGS.contains(&name.as_str()) {
+            self.new_line();
+        }
+        if (name == "td" || name == "th") && !self.line_is_blank() {
+            self.push(String::from(" │ "), Style::default().fg(Color::DarkGray))
+        }
+        let mut style = Style::default();
+        match name.as_str() {
+            "b" | "strong" | "th" => style = style.add_modifier(Modifier::BOLD),
+            "i" | "em" => style = style.add_modifier(Modifier::ITALIC),
+            "u" => style = style.add_modifier(Modifier::UNDERLINED),
+            "del" | "s" | "strike" => style = style.add_modifier(Modifier::CROSSED_OUT),
+            "pre" => {
+                self.code_block = Some(CodeBlock {
+                    language: None,
+                    code: String::new(),
+                })
+            }
+            "code" => {
+                // fenced code blocks are sent as `<pre><code class="language-x">`
+                if let Some(code_block) = &mut self.code_block {
+                    code_block.language = attribute(&attributes, "class")
+                        .and_then(|c| {
+                            c.split_whitespace()
+                                .find_map(|c| c.strip_prefix("language-"))
+                        })
+                        .map(str::to_owned);
+                }
+                style = style.fg(Color::Green)
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                style = style.add_modifier(Modifier::BOLD | Modifier::UNDERLINED)
+            }
+            "font" | "span" => {
+                let fg = attribute(&attributes, "data-mx-color")
+                    .or_else(|| attribute(&attributes, "color"))
+                    .and_then(parse_color);
+                if let Some(fg) = fg {
+                    style = style.fg(fg);
+                }
+                if let Some(bg) = attribute(&attributes, "data-mx-bg-color").and_then(parse_color) {
+                    style = style.bg(bg);
+                }
+            }
+            "a" => {
+                let href = attribute(&attributes, "href").unwrap_or("").to_owned();
+                let user_id = href
+                    .strip_prefix("https://matrix.to/#/")
+                    .map(|id| id.split('?').next().unwrap())
+                    .and_then(|id| UserId::try_from(id).ok());
+                if user_id.is_none() {
+                    style = style.fg(Color::Blue).add_modifier(Modifier::UNDERLINED);
+                }
+                self.link = Some(Link {
+                    href,
+                    text: String::new(),
+                    user_id,
+                });
+            }
+            "blockquote" => self.quote_depth += 1,
+            "ul" => self.lists.push(None),
+            "ol" => self.lists.push(Some(
+                attribute(&attributes, "start")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(1),
+            )),
+            "li" => {
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let bullet = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}{}. ", indent, *number - 1)
+                    }
+                    _ => format!("{}• ", indent),
+                };
+                self.push(bullet, Style::default());
+            }
+            "hr" => {
+                self.push(
+                    String::from("────────"),
+                    Style::default().fg(Color::DarkGray),
+                );
+                self.new_line();
+            }
+            "br" => match &mut self.code_block {
+                Some(code_block) => code_block.code.push('\n'),
+                None => self.break_line(),
+            },
+            "img" => {
+                let alt = attribute(&attributes, "alt").unwrap_or("image");
+                self.push(format!("[{}]", alt), self.style().fg(Color::DarkGray));
+            }
+            _ => {}
+        }
+        if !VOID_TAGS.contains(&name.as_str()) {
+            self.styles.push((name, style));
+        }
+    }
+
+    fn end(&mut self, name: String) {
+        if name == "mx-reply" {
+            self.reply_depth = self.reply_depth.saturating_sub(1);
+            return;
+        }
+        if self.reply_depth > 0 {
+            return;
+        }
+        // also closes the tags that were left open inside this one
+        if let Some(index) = self.styles.iter().rposition(|(n, _)| *n == name) {
+            self.styles.truncate(index);
+        } else {
+            return;
+        }
+        match name.as_str() {
+            "a" => {
+                if let Some(link) = self.link.take() {
+                    match link.user_id {
+                        Some(user_id) => {
+                            let name = match self
+                                .members
+                                .and_then(|members| members.display_name(&user_id))
+                            {
+                                Some(name) => name.into_owned(),
+                                None if link.text.trim().is_empty() => user_id.to_string(),
+                                None => link.text.trim().to_owned(),
+                            };
+                            let style = crate::ui::color_hash(&name).add_modifier(Modifier::BOLD);
+                            self.push(name, style);
+                        }
+                        // show where the link goes when its text doesn't
+                        None if link.text.trim() != link.href && !link.href.is_empty() => {
+                            self.push(
+                                format!(" <{}>", link.href),
+                                Style::default().fg(Color::DarkGray),
+                            );
+                        }
+                        None => {}
+                    }
+                }
+            }
+            "pre" => {
+                if let Some(code_block) = self.code_block.take() {
+                    let lines = self
+                        .highlighter
+                        .code_block(&code_block.code, code_block.language.as_deref());
+                    for line in lines {
+                        for span in line.0 {
+                            self.push(span.content.into_owned(), span.style);
+                        }
+                        self.break_line();
+                    }
+                }
+            }
+            "blockquote" => self.quote_depth = self.quote_depth.saturating_sub(1),
+            "ul" | "ol" => {
+                self.lists.pop();
+            }
+            _ => {}
+        }
+        if BLOCK_TAGS.contains(&name.as_str()) {
+            self.new_line();
+        }
+    }
+}
+
+/// Renders the HTML of a `formatted_body` to lines of styled text. Only the tags allowed by the
+/// Matrix spec are styled, the others only keep their text. Reply fallbacks are left out and
+/// mentions are shown as the display name of the user. Code blocks are highlighted.
+pub fn render(
+    html: &str,
+    members: Option<&Members>,
+    highlighter: &Highlighter,
+) -> Vec<Spans<'static>> {
+    let mut renderer = Renderer {
+        members,
+        highlighter,
+        lines: Vec::new(),
+        line: Vec::new(),
+        styles: Vec::new(),
+        lists: Vec::new(),
+        quote_depth: 0,
+        code_block: None,
+        reply_depth: 0,
+        link: None,
+    };
+    for token in tokenize(html) {
+        match token {
+            Token::Text(text) => renderer.text(text),
+            Token::Start { name, attributes } => renderer.start(name, attributes),
+            Token::End { name } => renderer.end(name),
+        }
+    }
+    renderer.new_line();
+    while renderer.lines.last().map_or(false, |l| l.width() == 0) {
+        renderer.lines.pop();
+    }
+    if renderer.lines.is_empty() {
+        renderer.lines.push(Spans::default());
+    }
+    renderer.lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_text(html: &str, members: Option<&Members>) -> Vec<String> {
+        let highlighter = Highlighter::new(crate::highlight::DEFAULT_THEME).unwrap();
+        render(html, members, &highlighter)
+            .iter()
+            .map(|line| line.0.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn strips_reply_fallback() {
+        let html = "<mx-reply><blockquote>\
+            <a href=\"https://matrix.to/#/!room:example.org/$event\">In reply to</a> \
+            <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>\
+            <br>original</blockquote></mx-reply>reply";
+        assert_eq!(render_text(html, None), ["reply"]);
+    }
+
+    #[test]
+    fn closes_unclosed_tags() {
+        let html = "<b>bold <i>both</b> plain";
+        let highlighter = Highlighter::new(crate::highlight::DEFAULT_THEME).unwrap();
+        let lines = render(html, None, &highlighter);
+        assert_eq!(lines.len(), 1);
+        let spans = &lines[0].0;
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].content, "bold ");
+        assert_eq!(spans[0].style.add_modifier, Modifier::BOLD);
+        assert_eq!(spans[1].content, "both");
+        assert_eq!(
+            spans[1].style.add_modifier,
+            Modifier::BOLD | Modifier::ITALIC
+        );
+        assert_eq!(spans[2].content, " plain");
+        assert_eq!(spans[2].style.add_modifier, Modifier::empty());
+    }
+
+    #[test]
+    fn keeps_text_of_unknown_tags() {
+        let html = "<custom title=\"a > b\">text</custom><p>paragraph</p>1 < 2";
+        assert_eq!(render_text(html, None), ["text", "paragraph", "1 < 2"]);
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            decode_entities("&lt;b&gt; &amp;amp; &#x1F600;&#65; &bogus; & &toolongtobeanentity;"),
+            "<b> &amp; 😀A &bogus; & &toolongtobeanentity;"
+        );
+        assert_eq!(
+            render_text("&lt;b&gt;not bold&lt;/b&gt;", None),
+            ["<b>not bold</b>"]
+        );
+    }
+
+    #[test]
+    fn renders_nested_lists() {
+        let html =
+            "<ul><li>one<ol start=\"3\"><li>two</li><li>three</li></ol></li><li>four</li></ul>";
+        assert_eq!(
+            render_text(html, None),
+            ["• one", "  3. two", "  4. three", "• four"]
+        );
+    }
+
+    #[test]
+    fn renders_mentions_with_display_names() {
+        let html = "<a href=\"https://matrix.to/#/@alice:example.org\">alice</a>: hi";
+        assert_eq!(render_text(html, None), ["alice: hi"]);
+        let mut members = Members::default();
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        members.update(alice, Some(String::from("Alice")), true);
+        assert_eq!(render_text(html, Some(&members)), ["Alice: hi"]);
+    }
+
+    #[test]
+    fn renders_code_blocks() {
+        let html = "<p>code:</p><pre><code class=\"language-rust\">fn main() {}\n</code></pre>";
+        assert_eq!(
+            render_text(html, None),
+            ["code:", "┌─ rust", "│ fn main() {}", "└─"]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::prelude::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use matrix_sdk::{
+    ruma::api::{
+        client::error::ErrorKind,
+        error::{FromHttpResponseError, ServerError},
+    },
+    Session,
+};
+
+use crate::config::AccountConfig;
+
+#[derive(Debug)]
+pub struct LoginError {
+    message: String,
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+fn session_path(store_path: &str) -> String {
+    format!("{}/{}", store_path, "session.json")
+}
+
+fn load_session(path: &str) -> Result<Option<Session>, Box<dyn std::error::Error>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+fn save_session(path: &str, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+    // the access token is as good as the password, keep it private
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(serde_json::to_string(session)?.as_bytes())?;
+    Ok(())
+}
+
+/// Returns `Some(soft_logout)` if the server rejected our access token.
+fn unknown_token(error: &matrix_sdk::HttpError) -> Option<bool> {
+    match error {
+        matrix_sdk::HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))) => {
+            match e.kind {
+                ErrorKind::UnknownToken { soft_logout } => Some(soft_logout),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Restores the session saved in the account's store if the server still accepts it, otherwise logs
+/// in again and saves the new session.
+///
+/// The device id of the first login comes from the config, or is assigned by the server if there
+/// is none in it. It is then reused for all subsequent logins since the crypto store is tied to it.
+pub async fn login(
+    account_config: &AccountConfig,
+    client: &mut matrix_sdk::Client,
+    login_token: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_path = session_path(&account_config.store_path);
+    let mut device_id = account_config.device_id.clone();
+
+    if let Some(session) = load_session(&session_path)? {
+        eprintln!("Restoring session...");
+        device_id = Some(session.device_id.to_string());
+        client.restore_login(session).await?;
+        use matrix_sdk::ruma::api::client::r0::account::whoami::Request;
+        match client.send(Request::new(), None).await {
+            Ok(_) => return Ok(()),
+            // the device still exists, we only need a new access token for it
+            Err(e) if unknown_token(&e) == Some(true) => {
+                eprintln!("The saved session has expired");
+            }
+            Err(e) if unknown_token(&e) == Some(false) => {
+                return Err(Box::new(LoginError {
+                    message: format!(
+                        "The device {} no longer exists on the server, remove {} to log in with a new device",
+                        device_id.unwrap_or_default(),
+                        account_config.store_path
+                    ),
+                }));
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+
+    let (session, restored) = match login_token {
+        Some(token) => (
+            token_login(client, token, device_id.as_deref()).await?,
+            false,
+        ),
+        None => new_login(account_config, client, device_id.as_deref()).await?,
+    };
+    // saved first, the login succeeded even if restoring it fails
+    save_session(&session_path, &session)?;
+    if !restored {
+        client.restore_login(session).await?;
+    }
+    Ok(())
+}
+
+/// Logs in with the first flow supported by both the server and the account. Also returns whether
+/// the client already uses the new session.
+async fn new_login(
+    account_config: &AccountConfig,
+    client: &matrix_sdk::Client,
+    device_id: Option<&str>,
+) -> Result<(Session, bool), Box<dyn std::error::Error>> {
+    use matrix_sdk::ruma::api::client::r0::session::get_login_types::{LoginType, Request};
+    let flows = client.send(Request::new(), None).await?.flows;
+    let supports_password = flows.iter().any(|f| matches!(f, LoginType::Password(_)));
+    let supports_sso = flows.iter().any(|f| matches!(f, LoginType::Sso(_)));
+
+    if supports_password {
+        if let Some(password) = account_config.password()? {
+            eprintln!("Logging in...");
+            let response = client
+                .login(&account_config.user, &password, device_id, Some("mient"))
+                .await?;
+            let session = Session {
+                access_token: response.access_token,
+                user_id: response.user_id,
+                device_id: response.device_id,
+            };
+            return Ok((session, true));
+        }
+    }
+    if supports_sso {
+        let token = sso_login_token(client).await?;
+        return Ok((token_login(client, &token, device_id).await?, false));
+    }
+    Err(Box::new(LoginError {
+        message: format!("No supported way to log in, the server offers {:?}", flows),
+    }))
+}
+
+/// Logs in with an `m.login.token` token, obtained out of band or through single sign-on.
+async fn token_login(
+    client: &matrix_sdk::Client,
+    token: &str,
+    device_id: Option<&str>,
+) -> Result<Session, Box<dyn std::error::Error>> {
+    use matrix_sdk::ruma::api::client::r0::session::login::{LoginInfo, Request};
+    eprintln!("Logging in with a token...");
+    let mut request = Request::new(LoginInfo::Token { token });
+    request.device_id = device_id.map(Into::into);
+    request.initial_device_display_name = Some("mient");
+    let response = client.send(request, None).await?;
+    Ok(Session {
+        access_token: response.access_token,
+        user_id: response.user_id,
+        device_id: response.device_id,
+    })
+}
+
+/// Has the user go through the server's single sign-on page, which redirects to a local loopback
+/// server that receives the login token.
+async fn sso_login_token(
+    client: &matrix_sdk::Client,
+) -> Result<String, Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_url = format!("http://{}/", listener.local_addr()?);
+    let mut sso_url = client
+        .homeserver()
+        .await
+        .join("_matrix/client/r0/login/sso/redirect")?;
+    sso_url
+        .query_pairs_mut()
+        .append_pair("redirectUrl", &redirect_url);
+    eprintln!("Open this URL to log in:");
+    eprintln!("{}", sso_url);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buffer = vec![0; 4096];
+        let read = stream.read(&mut buffer).await?;
+        let request = String::from_utf8_lossy(&buffer[..read]);
+        // GET /?loginToken=... HTTP/1.1
+        let token = request
+            .lines()
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|path| {
+                url::Url::parse(&redirect_url)
+                    .and_then(|u| u.join(path))
+                    .ok()
+            })
+            .and_then(|url| {
+                url.query_pairs()
+                    .find(|(key, _)| key == "loginToken")
+                    .map(|(_, value)| value.into_owned())
+            });
+        let body = match token {
+            Some(_) => "Logged in, you can close this page and go back to mient.",
+            None => "No login token received.",
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        if let Some(token) = token {
+            return Ok(token);
+        }
+    }
+}
+
+/// What mient and the SDK keep in the store of an account. The store can be a directory with other
+/// files, such as the data directory with the single account config, so only these are removed.
+const STORE_ENTRIES: &[&str] = &[
+    "matrix-sdk-state",
+    "matrix-sdk-crypto",
+    "session.json",
+    "pending.json",
+];
+
+/// Invalidates the session on the server, which also deletes its device, and wipes the account's
+/// store since its keys are now useless.
+pub async fn logout(
+    account_config: &AccountConfig,
+    client: &matrix_sdk::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use matrix_sdk::ruma::api::client::r0::session::logout::Request;
+    eprintln!("Logging out...");
+    client.send(Request::new(), None).await?;
+    for entry in STORE_ENTRIES {
+        let path = std::path::Path::new(&account_config.store_path).join(entry);
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Box::new(e)),
+            _ => {}
+        }
+    }
+    // only goes if nothing else is left in it
+    let _ = std::fs::remove_dir(&account_config.store_path);
+    Ok(())
+}
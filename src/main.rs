@@ -1,99 +1,124 @@
 mod app;
+mod cli;
+mod commands;
+mod completion;
 mod config;
 mod cross_signing;
+mod devices;
+mod editor;
+mod emoji;
 mod events;
+mod export;
+mod highlight;
+mod html;
 mod log;
+mod login;
 mod matrix;
+mod queue;
+mod rooms;
+mod send;
 mod state;
+mod tail;
 mod ui;
 mod utils;
 mod verification;
 
-use config::MientConfig;
+use structopt::StructOpt;
+
+use cli::{Command, DevicesCommand, KeysCommand, RoomsCommand};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     // tracing_subscriber::fmt::init();
+    if let Err(e) = run(cli::Opt::from_args()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(opt: cli::Opt) -> Result<(), Box<dyn std::error::Error>> {
     let home = std::env::var("HOME")?;
-    let mut config_path = format!("{}/{}", &home, ".config/mient/config.json");
-    let mut args: Vec<String> = Vec::new();
-    for arg in std::env::args() {
-        if arg.starts_with("--config=") {
-            config_path = arg.strip_prefix("--config=").unwrap().to_string()
-        } else {
-            args.push(arg)
-        }
+    let config_path = opt
+        .config
+        .unwrap_or_else(|| format!("{}/{}", &home, ".config/mient/config.json"));
+    let data_path = format!("{}/{}", home, ".local/share/mient");
+    let mient_config = config::MientConfig::get(&config_path, &data_path)?;
+    let ui_config = mient_config.ui.clone();
+    let mut account_config = mient_config.account(opt.account.as_deref())?;
+    if let Some(store_path) = opt.store {
+        account_config.store_path = store_path;
     }
-    let mient_config = config::MientConfig::get(&config_path)?;
 
-    let client_config =
-        matrix_sdk::ClientConfig::new().store_path(&format!("{}/{}", home, ".local/share/mient"));
-    let homeserver_url = url::Url::parse(&mient_config.homeserver)
+    std::fs::create_dir_all(&account_config.store_path)?;
+    let client_config = matrix_sdk::ClientConfig::new().store_path(&account_config.store_path);
+    let homeserver_url = url::Url::parse(&account_config.homeserver)
         .expect("Couldn't parse the homeserver URL, you might have forgotten to prefix https://");
     let mut client = matrix_sdk::Client::new_with_config(homeserver_url, client_config)?;
 
-    match args.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[1..] {
-        [] => {
-            login(&mient_config, &mut client).await?;
-            app::tui(client).await?
-        }
-        ["--cross-sign"] => {
-            login(&mient_config, &mut client).await?;
-            cross_signing::cross_sign(&client, &mient_config.password).await?;
-        }
-        ["--import-keys", path, password] => {
-            login(&mient_config, &mut client).await?;
-            client
-                .import_keys(path.into(), password)
-                .await
-                .map(|_| ())?
+    login::login(&account_config, &mut client, opt.login_token.as_deref()).await?;
+
+    match opt.command.unwrap_or(Command::Tui) {
+        Command::Tui => {
+            if app::tui(client.clone(), ui_config, &account_config.store_path).await? {
+                login::logout(&account_config, &client).await?;
+            }
         }
-        ["--export-keys", path, password] => {
-            login(&mient_config, &mut client).await?;
-            client.export_keys(path.into(), password, |_| true).await?
+        Command::Logout => login::logout(&account_config, &client).await?,
+        Command::CrossSign => {
+            cross_signing::cross_sign(&client, account_config.password()?.as_deref()).await?
         }
-        ["--list-devices"] => {
-            login(&mient_config, &mut client).await?;
-            let user_id = client.user_id().await.unwrap();
-            for device in client.get_user_devices(&user_id).await?.devices() {
-                println!(
-                    "Device: {}, Trust: {:?}, Name: {}",
-                    device.device_id(),
-                    device.local_trust_state(),
-                    device.display_name().as_deref().unwrap_or("")
-                );
+        Command::Keys { command } => match command {
+            KeysCommand::Import { file, password } => {
+                client.import_keys(file, &password).await.map(|_| ())?
             }
+            KeysCommand::Export { file, password } => {
+                client.export_keys(file, &password, |_| true).await?
+            }
+        },
+        Command::Send {
+            room,
+            text,
+            notice,
+            emote,
+            markdown,
+            reply_to,
+        } => {
+            let kind = if notice {
+                utils::MessageKind::Notice
+            } else if emote {
+                utils::MessageKind::Emote
+            } else {
+                utils::MessageKind::Text
+            };
+            send::send(&client, &room, text, kind, markdown, reply_to.as_deref()).await?
         }
-        ["--verify", device] => {
-            login(&mient_config, &mut client).await?;
-            verification::verify_device(client, device).await?;
-        }
-        _ => usage(),
+        Command::Rooms { command } => match command {
+            RoomsCommand::List => rooms::list(&client).await?,
+            RoomsCommand::Join { room } => rooms::join(&client, &room).await?,
+            RoomsCommand::Leave { room } => rooms::leave(&client, &room).await?,
+            RoomsCommand::Create {
+                name,
+                encrypted,
+                invite,
+            } => rooms::create(&client, name.as_deref(), encrypted, &invite).await?,
+        },
+        Command::Export {
+            room,
+            format,
+            since,
+            output,
+        } => export::export(&client, &room, format, since.as_deref(), output.as_deref()).await?,
+        Command::Tail { room } => tail::tail(&client, room.as_deref()).await?,
+        Command::Devices { command } => match command {
+            DevicesCommand::List => devices::list_devices(&client).await?,
+            DevicesCommand::Verify { device } => {
+                verification::verify_device(client, &device).await?
+            }
+            DevicesCommand::Delete { device } => {
+                devices::delete_device(&client, &account_config, &device).await?
+            }
+        },
     }
 
     Ok(())
 }
-
-async fn login(
-    mient_config: &MientConfig,
-    client: &mut matrix_sdk::Client,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Logging in...");
-    client
-        .login(
-            &mient_config.user,
-            &mient_config.password,
-            Some(&mient_config.device_id),
-            Some("mient"),
-        )
-        .await?;
-    Ok(())
-}
-
-fn usage() {
-    println!("Wrong arguments, must be either nothing or one of:");
-    println!("--import-keys <file> <password>");
-    println!("--export-keys <file> <password>");
-    println!("--list-devices");
-    println!("--verify <device>");
-}
@@ -1,36 +1,151 @@
+use std::convert::TryFrom;
+
 use async_trait::async_trait;
 use matrix_sdk::{
     room::Room,
     ruma::{
+        api::client::r0::message::get_message_events,
         events::{
             presence::PresenceEvent,
-            reaction::ReactionEventContent,
             room::{
-                aliases::AliasesEventContent,
-                avatar::AvatarEventContent,
-                canonical_alias::CanonicalAliasEventContent,
-                join_rules::JoinRulesEventContent,
-                member::MemberEventContent,
-                message::{feedback::FeedbackEventContent, MessageEventContent},
-                name::NameEventContent,
-                power_levels::PowerLevelsEventContent,
-                redaction::SyncRedactionEvent,
-                tombstone::TombstoneEventContent,
+                aliases::AliasesEventContent, avatar::AvatarEventContent,
+                canonical_alias::CanonicalAliasEventContent, join_rules::JoinRulesEventContent,
+                member::MemberEventContent, message::feedback::FeedbackEventContent,
+                name::NameEventContent, power_levels::PowerLevelsEventContent,
+                redaction::SyncRedactionEvent, tombstone::TombstoneEventContent,
             },
-            AnyMessageEvent, AnyRoomEvent, AnySyncMessageEvent, AnySyncRoomEvent,
-            StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
+            AnyMessageEvent, AnyRoomEvent, AnyStateEvent, AnySyncMessageEvent, AnySyncRoomEvent,
+            AnySyncStateEvent, StrippedStateEvent, SyncMessageEvent, SyncStateEvent,
         },
-        RoomId, UInt,
+        EventId, RoomId, UInt, UserId,
     },
 };
 
 use crate::{events::*, state};
 
+/// Catches up with the server, for the commands that need to know about our rooms without running
+/// the sync loop.
+pub async fn sync_once(client: &matrix_sdk::Client) -> matrix_sdk::Result<()> {
+    let mut sync_settings = matrix_sdk::SyncSettings::new();
+    if let Some(token) = client.sync_token().await {
+        sync_settings = sync_settings.token(token);
+    }
+    client.sync_once(sync_settings).await.map(|_| ())
+}
+
+/// Fetches a single event, decrypted if need be.
+pub async fn fetch_event(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> matrix_sdk::Result<Option<AnyRoomEvent>> {
+    use matrix_sdk::ruma::api::client::r0::context::get_context;
+    // only the messages endpoint decrypts events, so get a token right before the event and
+    // fetch it from there
+    let room = match client.get_room(room_id) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let mut request = get_context::Request::new(room_id, event_id);
+    request.limit = UInt::new(0).unwrap();
+    let context = client.send(request, None).await?;
+    let from = match context.start {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let mut request = get_message_events::Request::forward(room_id, &from);
+    request.limit = UInt::new(1).unwrap();
+    let response = room.messages(request).await?;
+    Ok(response
+        .chunk
+        .into_iter()
+        .filter_map(|e| e.deserialize().ok())
+        .find(|e| e.event_id() == event_id))
+}
+
+/// Queues a message for the current room, or its open thread, as a reply if a message is selected,
+/// and shows it right away.
+pub fn send_message(
+    state: &mut state::State,
+    text: &str,
+    kind: crate::utils::MessageKind,
+    markdown: bool,
+) {
+    let room = match state.current_room() {
+        Some(r) => r,
+        None => return,
+    };
+    // a message that isn't sent yet has no id to reply to, and state changes aren't messages
+    let replied_to = room
+        .selected_message()
+        .filter(|msg| msg.is_on_server() && !msg.is_state_change())
+        .map(|msg| &msg.event);
+    let content = crate::utils::make_message_content(text, kind, markdown, replied_to);
+    let mut message = crate::queue::PendingMessage::new(room.id.clone(), content);
+    if let Some(thread_id) = &room.open_thread {
+        let fallback_id = match replied_to {
+            Some(_) => None,
+            None => Some(
+                room.selected_list()
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|msg| msg.is_on_server())
+                    .map_or(thread_id, |msg| &msg.event.event_id)
+                    .clone(),
+            ),
+        };
+        message.thread = Some(crate::utils::ThreadRelation {
+            thread_id: thread_id.clone(),
+            fallback_id,
+        });
+    }
+    let echo = message.local_echo(state.user_id.clone());
+    let txn_id = message.txn_id.clone();
+    state.send_queue.push(message);
+    if let Some(room) = state.current_room_mut() {
+        room.selected_list_mut().push_local(echo, txn_id);
+    }
+}
+
+/// Sends an edit of one of our messages in the current room.
+pub fn send_edit(
+    state: &mut state::State,
+    client: &matrix_sdk::Client,
+    tx: &tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+    text: &str,
+    event_id: &EventId,
+) {
+    let room = match state.current_room() {
+        Some(r) => r,
+        None => return,
+    };
+    let id = room.id.clone();
+    let original = match room.find_message(event_id) {
+        Some(msg) => &msg.event,
+        None => return,
+    };
+    let content = match crate::utils::make_edit_content(text, state.config.markdown, original) {
+        Some(c) => c,
+        None => {
+            state.status = String::from("Only text messages can be edited");
+            return;
+        }
+    };
+    let message = matrix_sdk::ruma::events::AnyMessageEventContent::RoomMessage(content);
+    let client = client.clone();
+    crate::commands::spawn_command(tx, async move {
+        client.room_send(&id, message, None).await.map(|_| ())
+    });
+}
+
 pub fn send_read_receipt_current_room(client: matrix_sdk::Client, room: &state::Room) {
     let last_id = room
         .message_list
         .messages
-        .back()
+        .iter()
+        .rev()
+        .find(|msg| msg.is_on_server())
         .map(|msg| msg.event.event_id.clone());
     let room_id = room.id.clone();
     if let Some(last_read_id) = last_id {
@@ -45,6 +160,24 @@ pub fn send_read_receipt_current_room(client: matrix_sdk::Client, room: &state::
     }
 }
 
+/// Fetches up to `limit` (decrypted) events preceding the `from` pagination token, or `None` if we
+/// don't know that room.
+pub async fn messages_backward(
+    client: &matrix_sdk::Client,
+    room_id: &RoomId,
+    from: &str,
+    limit: u32,
+) -> matrix_sdk::Result<Option<get_message_events::Response>> {
+    let mut request = get_message_events::Request::backward(room_id, from);
+    request.limit = UInt::from(limit);
+
+    let room = match client.get_room(room_id) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    room.messages(request).await.map(Some)
+}
+
 pub fn fetch_old_messages(
     room_id: RoomId,
     room: &mut crate::state::Room,
@@ -66,20 +199,9 @@ pub fn fetch_old_messages(
         None => return,
     };
     tokio::task::spawn(async move {
-        let mut request =
-            matrix_sdk::ruma::api::client::r0::message::get_message_events::Request::backward(
-                &room_id,
-                &prev_batch,
-            );
-        request.limit = UInt::new(50).unwrap();
-
-        let room = match client.get_room(&room_id) {
-            Some(r) => r,
-            None => return,
-        };
-        let response = match room.messages(request).await {
-            Ok(r) => r,
-            Err(_) => return,
+        let response = match messages_backward(&client, &room_id, &prev_batch, 50).await {
+            Ok(Some(r)) => r,
+            _ => return,
         };
         if let Some(prev_batch) = response.end {
             if let Err(e) = tx.send(MatrixEvent::PrevBatch {
@@ -89,8 +211,8 @@ pub fn fetch_old_messages(
                 crate::log::error(&e.to_string());
             }
         };
-        for event in response.chunk {
-            let event = match event.deserialize() {
+        for raw in response.chunk {
+            let event = match raw.deserialize() {
                 Ok(e) => e,
                 Err(err) => {
                     crate::log::error(&err.to_string());
@@ -100,11 +222,17 @@ pub fn fetch_old_messages(
             match event {
                 AnyRoomEvent::Message(m) => match m {
                     AnyMessageEvent::RoomMessage(evt) => {
-                        tx.send(MatrixEvent::OldMessage { event: evt }).unwrap();
+                        tx.send(MatrixEvent::OldMessage {
+                            event: evt,
+                            thread_id: crate::utils::thread_id(raw.json()),
+                        })
+                        .unwrap();
                     }
                     AnyMessageEvent::Reaction(evt) => {
                         let relation = evt.content.relates_to;
                         tx.send(MatrixEvent::Reaction {
+                            room_id: evt.room_id,
+                            reaction_id: evt.event_id,
                             event_id: relation.event_id,
                             user_id: evt.sender,
                             emoji: relation.emoji,
@@ -113,17 +241,146 @@ pub fn fetch_old_messages(
                     }
                     _ => crate::log::info(&format!("{:?}", m)),
                 },
+                AnyRoomEvent::State(evt) => {
+                    if let Some(change) = state_change(&evt.into()) {
+                        tx.send(MatrixEvent::StateChange {
+                            room_id: room_id.clone(),
+                            change,
+                            old: true,
+                        })
+                        .unwrap();
+                    }
+                }
                 _ => crate::log::info(&format!("{:?}", event)),
             }
         }
-        crate::log::info("state");
-        for e in response.state {
-            crate::log::info(&format!("{:?}", e));
+        // the members who sent the messages, as they are lazy-loaded
+        for event in response.state {
+            if let Ok(AnyStateEvent::RoomMember(member)) = event.deserialize() {
+                if let Some(event) =
+                    member_event(room_id.clone(), &member.state_key, &member.content, true)
+                {
+                    tx.send(event).unwrap();
+                }
+            }
         }
-        crate::log::info("\n");
     });
 }
 
+/// Gets the joined members of the room, from the server the first time as they are lazy-loaded.
+pub fn fetch_members(
+    room: matrix_sdk::room::Joined,
+    tx: tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+) {
+    tokio::task::spawn(async move {
+        let members = match room.joined_members().await {
+            Ok(members) => members,
+            Err(e) => {
+                crate::log::error(&e.to_string());
+                return;
+            }
+        };
+        for member in members {
+            let event = MatrixEvent::Member {
+                room_id: room.room_id().clone(),
+                user_id: member.user_id().clone(),
+                name: member.display_name().map(str::to_owned),
+                present: true,
+                old: false,
+            };
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn member_event(
+    room_id: RoomId,
+    state_key: &str,
+    content: &MemberEventContent,
+    old: bool,
+) -> Option<MatrixEvent> {
+    use matrix_sdk::ruma::events::room::member::MembershipState;
+    Some(MatrixEvent::Member {
+        room_id,
+        user_id: UserId::try_from(state_key).ok()?,
+        name: content.displayname.clone(),
+        present: matches!(
+            content.membership,
+            MembershipState::Join | MembershipState::Invite
+        ),
+        old,
+    })
+}
+
+/// What a state event changed, to show it in the timeline, `None` for the ones not worth it.
+fn state_change(event: &AnySyncStateEvent) -> Option<state::StateChange> {
+    use matrix_sdk::ruma::events::room::member::MembershipState::*;
+    let (text, membership) = match event {
+        AnySyncStateEvent::RoomMember(member) => {
+            let previous = member.prev_content.as_ref();
+            let previous_name = previous.and_then(|c| c.displayname.as_deref());
+            let name = member
+                .content
+                .displayname
+                .as_deref()
+                .or(previous_name)
+                .unwrap_or(&member.state_key);
+            let own = member.sender.as_str() == member.state_key;
+            let text = match (previous.map(|c| &c.membership), &member.content.membership) {
+                (Some(Join), Join) => match member.content.displayname.as_deref() {
+                    // avatar changes aren't worth a line
+                    new if new == previous_name => return None,
+                    Some(new) => format!("changed their name to {}", new),
+                    None => String::from("removed their name"),
+                },
+                (_, Join) => String::from("joined"),
+                (Some(Invite), Leave) if own => String::from("rejected the invitation"),
+                (Some(Invite), Leave) => format!("revoked the invitation of {}", name),
+                (Some(Ban), Leave) => format!("unbanned {}", name),
+                (_, Leave) if own => String::from("left"),
+                (_, Leave) => format!("kicked {}", name),
+                (_, Ban) => format!("banned {}", name),
+                (_, Invite) => format!("invited {}", name),
+                (_, Knock) => String::from("asked to join"),
+                _ => return None,
+            };
+            (text, true)
+        }
+        AnySyncStateEvent::RoomName(event) => match event.content.name() {
+            Some(name) => (format!("changed the room name to {}", name), false),
+            None => (String::from("removed the room name"), false),
+        },
+        AnySyncStateEvent::RoomTopic(event) if event.content.topic.is_empty() => {
+            (String::from("removed the topic"), false)
+        }
+        AnySyncStateEvent::RoomTopic(event) => (
+            format!("changed the topic to {}", event.content.topic),
+            false,
+        ),
+        AnySyncStateEvent::RoomAvatar(_) => (String::from("changed the room avatar"), false),
+        AnySyncStateEvent::RoomEncryption(_) => (String::from("enabled encryption"), false),
+        _ => return None,
+    };
+    Some(state::StateChange {
+        event_id: event.event_id().clone(),
+        sender: event.sender().clone(),
+        origin_server_ts: *event.origin_server_ts(),
+        text,
+        membership,
+    })
+}
+
+fn room_id(room: &Room) -> RoomId {
+    use matrix_sdk::room::Room::*;
+    match room {
+        Joined(r) => r.room_id().clone(),
+        Left(r) => r.room_id().clone(),
+        Invited(r) => r.room_id().clone(),
+    }
+}
+
 pub struct MatrixBroker {
     pub tx: tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
 }
@@ -139,25 +396,48 @@ impl MatrixBroker {
         }
     }
 
-    fn handle_timeline(&self, timeline: matrix_sdk::deserialized_responses::Timeline) {
-        for event in timeline
-            .events
-            .iter()
-            .filter_map(|e| e.event.deserialize().ok())
-        {
+    fn handle_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: matrix_sdk::deserialized_responses::Timeline,
+    ) {
+        for raw in timeline.events.iter().map(|e| &e.event) {
+            let event = match raw.deserialize() {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
             match event {
-                AnySyncRoomEvent::Message(msg) => {
-                    if let AnySyncMessageEvent::Reaction(evt) = msg {
-                        // TODO check if still applicable
+                AnySyncRoomEvent::Message(msg) => match msg {
+                    // handled here rather than in on_room_message to see the thread relations in
+                    // the JSON
+                    AnySyncMessageEvent::RoomMessage(evt) => {
+                        self.publish(MatrixEvent::NewMessage {
+                            event: evt.into_full_event(room_id.clone()),
+                            thread_id: crate::utils::thread_id(raw.json()),
+                        })
+                    }
+                    // only published from here, on_room_reaction would publish them twice
+                    AnySyncMessageEvent::Reaction(evt) => {
                         let relation = evt.content.relates_to;
                         self.publish(MatrixEvent::Reaction {
+                            room_id: room_id.clone(),
+                            reaction_id: evt.event_id,
                             event_id: relation.event_id,
                             user_id: evt.sender,
                             emoji: relation.emoji,
                         });
                     }
+                    _ => {}
+                },
+                AnySyncRoomEvent::State(evt) => {
+                    if let Some(change) = state_change(&evt) {
+                        self.publish(MatrixEvent::StateChange {
+                            room_id: room_id.clone(),
+                            change,
+                            old: false,
+                        });
+                    }
                 }
-                AnySyncRoomEvent::State(_) => {}
                 AnySyncRoomEvent::RedactedMessage(_) => {}
                 AnySyncRoomEvent::RedactedState(_) => {}
             }
@@ -173,7 +453,10 @@ impl MatrixBroker {
                 id: room_id.clone(),
                 count: room.unread_notifications.notification_count,
             });
-            self.handle_timeline(room.timeline);
+            self.handle_timeline(&room_id, room.timeline);
+        }
+        for (room_id, _) in response.rooms.leave {
+            self.publish(MatrixEvent::RoomLeft { id: room_id });
         }
         for event in response.to_device.events {
             crate::log::info(&format!("{:?}", event));
@@ -185,9 +468,10 @@ impl MatrixBroker {
 #[async_trait]
 #[allow(unused_must_use)]
 impl matrix_sdk::EventHandler for MatrixBroker {
-    async fn on_room_member(&self, _: Room, event: &SyncStateEvent<MemberEventContent>) {
-        // TODO as ref
-        crate::log::info(&format!("on room member {:?}", event));
+    async fn on_room_member(&self, room: Room, event: &SyncStateEvent<MemberEventContent>) {
+        if let Some(event) = member_event(room_id(&room), &event.state_key, &event.content, false) {
+            self.publish(event);
+        }
     }
 
     async fn on_room_name(&self, room: Room, event: &SyncStateEvent<NameEventContent>) {
@@ -202,14 +486,6 @@ impl matrix_sdk::EventHandler for MatrixBroker {
         }
     }
 
-    async fn on_room_message(&self, room: Room, event: &SyncMessageEvent<MessageEventContent>) {
-        if let Room::Joined(room) = room {
-            self.publish(MatrixEvent::NewMessage {
-                event: event.clone().into_full_event(room.room_id().clone()),
-            });
-        }
-    }
-
     async fn on_room_message_feedback(
         &self,
         _: Room,
@@ -218,23 +494,9 @@ impl matrix_sdk::EventHandler for MatrixBroker {
         crate::log::info(&format!("on room msg fb {:?}", event));
     }
 
-    // TODO never get in here? what is it for?
-    async fn on_room_reaction(&self, _: Room, event: &SyncMessageEvent<ReactionEventContent>) {
-        self.publish(MatrixEvent::Reaction {
-            event_id: event.content.relates_to.event_id.clone(),
-            user_id: event.sender.clone(),
-            emoji: event.content.relates_to.emoji.clone(),
-        });
-    }
-
     async fn on_room_redaction(&self, room_state: Room, event: &SyncRedactionEvent) {
-        use matrix_sdk::room::Room::*;
         self.publish(MatrixEvent::Redaction {
-            room_id: match room_state {
-                Joined(r) => r.room_id().clone(),
-                Left(r) => r.room_id().clone(),
-                Invited(r) => r.room_id().clone(),
-            },
+            room_id: room_id(&room_state),
             redacted_id: event.redacts.clone(),
         });
     }
@@ -245,7 +507,11 @@ impl matrix_sdk::EventHandler for MatrixBroker {
 
     async fn on_room_tombstone(&self, _: Room, _: &SyncStateEvent<TombstoneEventContent>) {}
 
-    async fn on_state_member(&self, _: Room, _: &SyncStateEvent<MemberEventContent>) {}
+    async fn on_state_member(&self, room: Room, event: &SyncStateEvent<MemberEventContent>) {
+        if let Some(event) = member_event(room_id(&room), &event.state_key, &event.content, false) {
+            self.publish(event);
+        }
+    }
 
     async fn on_state_name(&self, room: Room, _: &SyncStateEvent<NameEventContent>) {
         // TODO test what happens if I get some history, might start using the older names
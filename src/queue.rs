@@ -0,0 +1,159 @@
+use std::convert::TryFrom;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use matrix_sdk::ruma::{
+    events::{room::message::MessageEventContent, AnyMessageEventContent, MessageEvent, Unsigned},
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
+};
+use matrix_sdk::uuid::Uuid;
+use serde::{Deserialize, Serialize};
+
+use crate::events::MatrixEvent;
+use crate::utils::ThreadRelation;
+
+/// A message that the server hasn't accepted yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub room_id: RoomId,
+    pub txn_id: String,
+    pub content: MessageEventContent,
+    #[serde(default)]
+    pub thread: Option<ThreadRelation>,
+}
+
+impl PendingMessage {
+    pub fn new(room_id: RoomId, content: MessageEventContent) -> Self {
+        Self {
+            room_id,
+            txn_id: Uuid::new_v4().to_string(),
+            content,
+            thread: None,
+        }
+    }
+
+    /// The event shown in the timeline until the server sends it back, with a placeholder id.
+    pub fn local_echo(&self, sender: UserId) -> MessageEvent<MessageEventContent> {
+        let mut unsigned = Unsigned::default();
+        unsigned.transaction_id = Some(self.txn_id.clone());
+        MessageEvent {
+            content: self.content.clone(),
+            event_id: EventId::try_from(format!("$local{}", self.txn_id.replace('-', ""))).unwrap(),
+            sender,
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            room_id: self.room_id.clone(),
+            unsigned,
+        }
+    }
+}
+
+/// Sends the messages one after the other, in the order they were written. The unsent ones are
+/// saved so that they are sent on the next launch if mient is closed before.
+pub struct SendQueue {
+    tx: tokio::sync::mpsc::UnboundedSender<PendingMessage>,
+    pending: Vec<PendingMessage>,
+    path: String,
+}
+
+impl SendQueue {
+    pub fn new(
+        client: matrix_sdk::Client,
+        event_tx: tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
+        store_path: &str,
+    ) -> Self {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PendingMessage>();
+        tokio::task::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                // the server ignores the messages it already got with the same transaction id
+                let txn_id = Uuid::parse_str(&message.txn_id).ok();
+                let content = match &message.thread {
+                    Some(thread) => crate::utils::make_thread_content(message.content, thread),
+                    None => AnyMessageEventContent::RoomMessage(message.content),
+                };
+                let event = match client.room_send(&message.room_id, content, txn_id).await {
+                    Ok(response) => MatrixEvent::MessageSent {
+                        room_id: message.room_id,
+                        txn_id: message.txn_id,
+                        event_id: response.event_id,
+                    },
+                    Err(e) => MatrixEvent::MessageFailed {
+                        room_id: message.room_id,
+                        txn_id: message.txn_id,
+                        error: e.to_string(),
+                    },
+                };
+                if event_tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let path = format!("{}/pending.json", store_path);
+        let pending = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                crate::log::error(&format!("Couldn't read the unsent messages: {}", e));
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        Self { tx, pending, path }
+    }
+
+    /// The messages that weren't sent before quitting last time.
+    pub fn pending(&self) -> &[PendingMessage] {
+        &self.pending
+    }
+
+    fn save(&self) {
+        // unsent messages of encrypted rooms are in clear, keep them private
+        let saved = serde_json::to_string(&self.pending)
+            .map_err(|e| e.to_string())
+            .and_then(|json| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&self.path)
+                    .and_then(|mut file| file.write_all(json.as_bytes()))
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = saved {
+            crate::log::error(&format!("Couldn't save the unsent messages: {}", e));
+        }
+    }
+
+    pub fn push(&mut self, message: PendingMessage) {
+        self.pending.push(message.clone());
+        self.save();
+        self.send(message);
+    }
+
+    /// Sends the messages of a previous launch, or one that failed.
+    pub fn retry(&mut self, txn_id: Option<&str>) {
+        let messages = self
+            .pending
+            .iter()
+            .filter(|m| txn_id.map_or(true, |id| id == m.txn_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for message in messages {
+            self.send(message);
+        }
+    }
+
+    fn send(&self, message: PendingMessage) {
+        if let Err(e) = self.tx.send(message) {
+            crate::log::error(&e.to_string());
+        }
+    }
+
+    /// Forgets a message, once sent or when giving up on it.
+    pub fn remove(&mut self, txn_id: &str) {
+        let count = self.pending.len();
+        self.pending.retain(|m| m.txn_id != txn_id);
+        if self.pending.len() != count {
+            self.save();
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::convert::TryFrom;
+
+use matrix_sdk::ruma::{
+    events::{room::encryption::EncryptionEventContent, AnyInitialStateEvent, InitialStateEvent},
+    serde::Raw,
+    EventEncryptionAlgorithm, RoomAliasId, RoomId, RoomIdOrAliasId, UserId,
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct RoomInfo {
+    id: RoomId,
+    name: String,
+    alias: Option<RoomAliasId>,
+    membership: &'static str,
+    encrypted: bool,
+    unread: u64,
+    highlights: u64,
+}
+
+#[derive(Serialize)]
+struct RoomResult<'a> {
+    room_id: &'a RoomId,
+}
+
+async fn room_info(
+    room: &matrix_sdk::room::Common,
+    membership: &'static str,
+) -> Result<RoomInfo, matrix_sdk::Error> {
+    let counts = room.unread_notification_counts();
+    Ok(RoomInfo {
+        id: room.room_id().clone(),
+        name: room.display_name().await?,
+        alias: room.canonical_alias(),
+        membership,
+        encrypted: room.is_encrypted(),
+        unread: counts.notification_count,
+        highlights: counts.highlight_count,
+    })
+}
+
+/// Prints our rooms as JSON, one per line.
+pub async fn list(client: &matrix_sdk::Client) -> Result<(), Box<dyn std::error::Error>> {
+    crate::matrix::sync_once(client).await?;
+    let mut rooms = Vec::new();
+    for room in client.joined_rooms() {
+        rooms.push(room_info(&room, "join").await?);
+    }
+    for room in client.invited_rooms() {
+        rooms.push(room_info(&room, "invite").await?);
+    }
+    for room in client.left_rooms() {
+        rooms.push(room_info(&room, "leave").await?);
+    }
+    for room in rooms {
+        println!("{}", serde_json::to_string(&room)?);
+    }
+    Ok(())
+}
+
+pub async fn join(
+    client: &matrix_sdk::Client,
+    room: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let room = RoomIdOrAliasId::try_from(room)?;
+    let response = client.join_room_by_id_or_alias(&room, &[]).await?;
+    println!(
+        "{}",
+        serde_json::to_string(&RoomResult {
+            room_id: &response.room_id
+        })?
+    );
+    Ok(())
+}
+
+/// Leaves a joined room or rejects an invitation.
+pub async fn leave(
+    client: &matrix_sdk::Client,
+    room: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    crate::matrix::sync_once(client).await?;
+    let room_id = crate::send::resolve_room(client, room).await?;
+    if let Some(room) = client.get_joined_room(&room_id) {
+        room.leave().await?;
+    } else if let Some(room) = client.get_invited_room(&room_id) {
+        room.reject_invitation().await?;
+    } else {
+        return Err(format!("Not in room {}", room_id).into());
+    }
+    println!(
+        "{}",
+        serde_json::to_string(&RoomResult { room_id: &room_id })?
+    );
+    Ok(())
+}
+
+pub async fn create(
+    client: &matrix_sdk::Client,
+    name: Option<&str>,
+    encrypted: bool,
+    invite: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    use matrix_sdk::ruma::api::client::r0::room::create_room::Request;
+    let invite = invite
+        .iter()
+        .map(|user| UserId::try_from(user.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut initial_state: Vec<Raw<AnyInitialStateEvent>> = Vec::new();
+    if encrypted {
+        let content = EncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2);
+        initial_state.push(
+            AnyInitialStateEvent::RoomEncryption(InitialStateEvent {
+                content,
+                state_key: String::new(),
+            })
+            .into(),
+        );
+    }
+
+    let mut request = Request::new();
+    request.name = name;
+    request.invite = &invite;
+    request.initial_state = &initial_state;
+    let response = client.create_room(request).await?;
+    println!(
+        "{}",
+        serde_json::to_string(&RoomResult {
+            room_id: &response.room_id
+        })?
+    );
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::convert::TryFrom;
+use std::io::prelude::Read;
+
+use matrix_sdk::ruma::{
+    events::{AnyMessageEvent, AnyMessageEventContent, AnyRoomEvent},
+    EventId, RoomAliasId, RoomId,
+};
+
+use crate::utils::MessageKind;
+
+/// Accepts either a room id or a room alias.
+pub async fn resolve_room(
+    client: &matrix_sdk::Client,
+    room: &str,
+) -> Result<RoomId, Box<dyn std::error::Error>> {
+    if room.starts_with('#') {
+        use matrix_sdk::ruma::api::client::r0::alias::get_alias::Request;
+        let alias = RoomAliasId::try_from(room)?;
+        Ok(client.send(Request::new(&alias), None).await?.room_id)
+    } else {
+        Ok(RoomId::try_from(room)?)
+    }
+}
+
+/// Sends a message and prints its event id once the server has accepted it.
+pub async fn send(
+    client: &matrix_sdk::Client,
+    room: &str,
+    text: Option<String>,
+    kind: MessageKind,
+    markdown: bool,
+    reply_to: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = match text.filter(|t| t != "-") {
+        Some(t) => t,
+        None => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            input.trim_end_matches('\n').to_owned()
+        }
+    };
+    if text.is_empty() {
+        return Err("Not sending an empty message".into());
+    }
+
+    crate::matrix::sync_once(client).await?;
+    let room_id = resolve_room(client, room).await?;
+    let room = client
+        .get_joined_room(&room_id)
+        .ok_or_else(|| format!("Not in room {}", room_id))?;
+
+    let replied_to = match reply_to {
+        Some(event_id) => {
+            let event_id = EventId::try_from(event_id)?;
+            match crate::matrix::fetch_event(client, &room_id, &event_id).await? {
+                Some(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(event))) => Some(event),
+                _ => return Err(format!("Couldn't find the message {}", event_id).into()),
+            }
+        }
+        None => None,
+    };
+
+    let content = crate::utils::make_message_content(&text, kind, markdown, replied_to.as_ref());
+    let response = room
+        .send(AnyMessageEventContent::RoomMessage(content), None)
+        .await?;
+    println!("{}", response.event_id);
+    Ok(())
+}
@@ -1,22 +1,146 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 
 use matrix_sdk::ruma::{
-    events::{room::message::MessageEventContent, MessageEvent},
-    EventId, RoomId, UserId,
+    events::{
+        room::message::{MessageEventContent, MessageType, NoticeMessageEventContent, Relation},
+        MessageEvent, Unsigned,
+    },
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
+use serde::Serialize;
 
+use crate::config::UiConfig;
+use crate::editor::{Draft, Editor};
+use crate::emoji::EmojiPicker;
 use crate::events::MatrixEvent;
+use crate::highlight::{Highlighter, DEFAULT_THEME};
+use crate::queue::SendQueue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendState {
+    Sending,
+    Failed(String),
+    /// The server accepted it, but it hasn't come back from sync yet.
+    Sent,
+}
+
+/// What we know about one of our messages until the server sends it back.
+#[derive(Debug, Clone)]
+pub struct LocalEcho {
+    pub txn_id: String,
+    pub state: SendState,
+}
+
+/// A state event, shown in the timeline as a line saying what changed.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateChange {
+    pub event_id: EventId,
+    pub sender: UserId,
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    /// What the sender did, to follow their name.
+    pub text: String,
+    /// Joins, leaves and the like, which come in runs.
+    pub membership: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub redacted: bool,
     pub event: MessageEvent<MessageEventContent>,
+    /// The `m.replace` events, oldest first.
+    pub edits: Vec<MessageEvent<MessageEventContent>>,
+    pub local_echo: Option<LocalEcho>,
+    /// For the entries standing for state events, what changed. Membership changes in a row are
+    /// grouped in one entry.
+    pub state_changes: Vec<StateChange>,
+    /// Whether the grouped membership changes are shown one per line.
+    pub expanded: bool,
+}
+
+impl Message {
+    pub fn new(event: MessageEvent<MessageEventContent>) -> Self {
+        Self {
+            redacted: false,
+            event,
+            edits: Vec::new(),
+            local_echo: None,
+            state_changes: Vec::new(),
+            expanded: false,
+        }
+    }
+
+    /// An entry for a state event, which has no content of its own so it gets the text.
+    fn from_state_change(room_id: RoomId, change: StateChange) -> Self {
+        let content = MessageEventContent::new(MessageType::Notice(
+            NoticeMessageEventContent::plain(change.text.clone()),
+        ));
+        let mut message = Self::new(MessageEvent {
+            content,
+            event_id: change.event_id.clone(),
+            sender: change.sender.clone(),
+            origin_server_ts: change.origin_server_ts,
+            room_id,
+            unsigned: Unsigned::default(),
+        });
+        message.state_changes.push(change);
+        message
+    }
+
+    pub fn is_state_change(&self) -> bool {
+        !self.state_changes.is_empty()
+    }
+
+    fn is_membership_run(&self) -> bool {
+        self.state_changes.first().map_or(false, |c| c.membership)
+    }
+
+    /// Whether the message has an id yet, which replies, edits and reactions need.
+    pub fn is_on_server(&self) -> bool {
+        self.local_echo
+            .as_ref()
+            .map_or(true, |echo| echo.state == SendState::Sent)
+    }
+
+    /// The content of the last edit, or the original one.
+    pub fn content(&self) -> &MessageEventContent {
+        self.edits
+            .last()
+            .and_then(|edit| edit.content.new_content.as_deref())
+            .unwrap_or(&self.event.content)
+    }
+
+    fn add_edit(&mut self, edit: MessageEvent<MessageEventContent>) {
+        // only the sender can edit a message
+        if edit.sender != self.event.sender || edit.content.new_content.is_none() {
+            return;
+        }
+        if self.edits.iter().any(|e| e.event_id == edit.event_id) {
+            return;
+        }
+        let index = self
+            .edits
+            .iter()
+            .position(|e| e.origin_server_ts > edit.origin_server_ts)
+            .unwrap_or(self.edits.len());
+        self.edits.insert(index, edit);
+    }
+}
+
+/// The id of the message this event edits, if it is an edit.
+fn replaced_id(event: &MessageEvent<MessageEventContent>) -> Option<&EventId> {
+    match &event.content.relates_to {
+        Some(Relation::Replacement(replacement)) => Some(&replacement.event_id),
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
 pub struct MessageList {
     pub messages: VecDeque<Message>,
     pub current_index: usize,
+    // edits of messages that haven't been fetched yet, by the id of the message they replace
+    pending_edits: HashMap<EventId, Vec<MessageEvent<MessageEventContent>>>,
 }
 
 impl MessageList {
@@ -24,22 +148,332 @@ impl MessageList {
         Self {
             messages: VecDeque::new(),
             current_index: 0,
+            pending_edits: HashMap::new(),
+        }
+    }
+
+    /// Applies the edit to the message it replaces, or keeps it until that message is fetched.
+    fn push_edit(&mut self, replaced_id: EventId, event: MessageEvent<MessageEventContent>) {
+        match self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|msg| msg.event.event_id == replaced_id)
+        {
+            Some(message) => message.add_edit(event),
+            None => self
+                .pending_edits
+                .entry(replaced_id)
+                .or_insert_with(Vec::new)
+                .push(event),
+        }
+    }
+
+    fn make_message(&mut self, event: MessageEvent<MessageEventContent>) -> Message {
+        let mut message = Message::new(event);
+        for edit in self
+            .pending_edits
+            .remove(&message.event.event_id)
+            .unwrap_or_default()
+        {
+            message.add_edit(edit);
+        }
+        message
+    }
+
+    pub fn push_new(&mut self, event: MessageEvent<MessageEventContent>) {
+        if let Some(replaced_id) = replaced_id(&event).cloned() {
+            return self.push_edit(replaced_id, event);
+        }
+        // our own message coming back
+        let local_echo = self
+            .messages
+            .iter_mut()
+            .rev()
+            .find(|msg| match &msg.local_echo {
+                Some(echo) => {
+                    event.unsigned.transaction_id.as_ref() == Some(&echo.txn_id)
+                        || msg.event.event_id == event.event_id
+                }
+                None => false,
+            });
+        if let Some(message) = local_echo {
+            message.event = event;
+            message.local_echo = None;
+            return;
+        }
+        let message = self.make_message(event);
+        if self.current_index == self.messages.len() {
+            self.current_index += 1;
         }
+        self.messages.push_back(message);
     }
 
-    pub fn push_new(&mut self, message: Message) {
+    pub fn push_local(&mut self, event: MessageEvent<MessageEventContent>, txn_id: String) {
+        let mut message = Message::new(event);
+        message.local_echo = Some(LocalEcho {
+            txn_id,
+            state: SendState::Sending,
+        });
         if self.current_index == self.messages.len() {
             self.current_index += 1;
         }
         self.messages.push_back(message);
     }
 
-    pub fn push_old(&mut self, message: Message) {
+    pub fn local_echo_mut(&mut self, txn_id: &str) -> Option<&mut Message> {
+        self.messages.iter_mut().rev().find(|msg| {
+            msg.local_echo
+                .as_ref()
+                .map_or(false, |echo| echo.txn_id == txn_id)
+        })
+    }
+
+    pub fn remove_local_echo(&mut self, txn_id: &str) {
+        let index = self.messages.iter().position(|msg| {
+            msg.local_echo
+                .as_ref()
+                .map_or(false, |echo| echo.txn_id == txn_id)
+        });
+        if let Some(index) = index {
+            self.messages.remove(index);
+            if self.current_index > index {
+                self.current_index -= 1;
+            }
+        }
+    }
+
+    fn find(&self, event_id: &EventId) -> Option<&Message> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|msg| &msg.event.event_id == event_id)
+    }
+
+    fn find_mut(&mut self, event_id: &EventId) -> Option<&mut Message> {
+        self.messages
+            .iter_mut()
+            .rev()
+            .find(|msg| &msg.event.event_id == event_id)
+    }
+
+    /// The selected message, if there is one.
+    pub fn selected(&self) -> Option<&Message> {
+        self.messages.get(self.current_index)
+    }
+
+    /// Adds a state event, to the run of membership changes it follows if `group` is set.
+    fn push_state_change(&mut self, room_id: RoomId, change: StateChange, old: bool, group: bool) {
+        let neighbour = if old {
+            self.messages.front_mut()
+        } else {
+            self.messages.back_mut()
+        };
+        let run = neighbour.filter(|msg| group && change.membership && msg.is_membership_run());
+        if let Some(message) = run {
+            if old {
+                message.state_changes.insert(0, change);
+            } else {
+                message.state_changes.push(change);
+            }
+            return;
+        }
+        let message = Message::from_state_change(room_id, change);
+        if old {
+            self.messages.push_front(message);
+            self.current_index += 1;
+        } else {
+            if self.current_index == self.messages.len() {
+                self.current_index += 1;
+            }
+            self.messages.push_back(message);
+        }
+    }
+
+    pub fn push_old(&mut self, event: MessageEvent<MessageEventContent>) {
+        if let Some(replaced_id) = replaced_id(&event).cloned() {
+            return self.push_edit(replaced_id, event);
+        }
+        let message = self.make_message(event);
         self.messages.push_front(message);
         self.current_index += 1;
     }
 }
 
+#[derive(Debug)]
+struct Reaction {
+    event_id: EventId,
+    user_id: UserId,
+    emoji: String,
+}
+
+/// How many messages that aren't loaded yet can have their reactions kept until they are.
+const MAX_UNLOADED_REACTION_TARGETS: usize = 256;
+
+/// The reactions of a room, by reaction event id so that they can be redacted.
+#[derive(Debug, Default)]
+pub struct Reactions {
+    reactions: HashMap<EventId, Reaction>,
+    // ids of the reactions to each message, in the order they were received
+    by_message: HashMap<EventId, Vec<EventId>>,
+    // the reacted to messages that aren't in the timeline, oldest first, as the history comes
+    // with reactions before the messages they react to
+    unloaded: VecDeque<EventId>,
+}
+
+impl Reactions {
+    /// `loaded` tells whether the message reacted to is in the timeline. The reactions to the
+    /// oldest unloaded messages are dropped when there are too many of them.
+    pub fn add(
+        &mut self,
+        reaction_id: EventId,
+        event_id: EventId,
+        user_id: UserId,
+        emoji: String,
+        loaded: bool,
+    ) {
+        // the same reaction can come from both pagination and sync
+        if self.reactions.contains_key(&reaction_id) {
+            return;
+        }
+        if !loaded && !self.unloaded.contains(&event_id) {
+            self.unloaded.push_back(event_id.clone());
+            if self.unloaded.len() > MAX_UNLOADED_REACTION_TARGETS {
+                if let Some(oldest) = self.unloaded.pop_front() {
+                    self.remove_message(&oldest);
+                }
+            }
+        }
+        self.by_message
+            .entry(event_id.clone())
+            .or_insert_with(Vec::new)
+            .push(reaction_id.clone());
+        self.reactions.insert(
+            reaction_id,
+            Reaction {
+                event_id,
+                user_id,
+                emoji,
+            },
+        );
+    }
+
+    /// Returns false if there is no reaction with that id.
+    pub fn remove(&mut self, reaction_id: &EventId) -> bool {
+        let reaction = match self.reactions.remove(reaction_id) {
+            Some(r) => r,
+            None => return false,
+        };
+        if let Some(ids) = self.by_message.get_mut(&reaction.event_id) {
+            ids.retain(|id| id != reaction_id);
+            if ids.is_empty() {
+                self.by_message.remove(&reaction.event_id);
+            }
+        }
+        true
+    }
+
+    /// The reactions to the message are kept for as long as it is.
+    fn message_loaded(&mut self, event_id: &EventId) {
+        self.unloaded.retain(|id| id != event_id);
+    }
+
+    /// Forgets the reactions to a message.
+    pub fn remove_message(&mut self, event_id: &EventId) {
+        self.unloaded.retain(|id| id != event_id);
+        for reaction_id in self.by_message.remove(event_id).unwrap_or_default() {
+            self.reactions.remove(&reaction_id);
+        }
+    }
+
+    /// The id of the reaction of that user with that emoji to the message.
+    pub fn find(&self, event_id: &EventId, user_id: &UserId, emoji: &str) -> Option<&EventId> {
+        self.by_message.get(event_id)?.iter().find(|id| {
+            let reaction = &self.reactions[*id];
+            &reaction.user_id == user_id && reaction.emoji == emoji
+        })
+    }
+
+    /// The emoji used to react to the message with the users who did, in the order they were first
+    /// used.
+    pub fn aggregated(&self, event_id: &EventId) -> Vec<(&str, Vec<&UserId>)> {
+        let mut aggregated: Vec<(&str, Vec<&UserId>)> = Vec::new();
+        for id in self.by_message.get(event_id).into_iter().flatten() {
+            let reaction = &self.reactions[id];
+            match aggregated.iter_mut().find(|(e, _)| *e == reaction.emoji) {
+                Some((_, users)) => users.push(&reaction.user_id),
+                None => aggregated.push((&reaction.emoji, vec![&reaction.user_id])),
+            }
+        }
+        aggregated
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    name: Option<String>,
+    // joined or invited
+    present: bool,
+}
+
+/// The display names of the members of a room, from their `m.room.member` events.
+#[derive(Debug, Default)]
+pub struct Members {
+    members: HashMap<UserId, Member>,
+    // how many present members have each name, to tell apart the ones sharing one
+    name_counts: HashMap<String, usize>,
+}
+
+impl Members {
+    /// Members who leave keep their last name for their messages.
+    pub fn update(&mut self, user_id: UserId, name: Option<String>, present: bool) {
+        let member = self.members.entry(user_id).or_insert(Member {
+            name: None,
+            present: false,
+        });
+        if let Some(old_name) = member.name.as_ref().filter(|_| member.present) {
+            let count = self.name_counts.get(old_name).copied().unwrap_or(1);
+            if count <= 1 {
+                self.name_counts.remove(old_name);
+            } else {
+                self.name_counts.insert(old_name.clone(), count - 1);
+            }
+        }
+        if present || name.is_some() {
+            member.name = name.filter(|n| !n.is_empty());
+        }
+        member.present = present;
+        if let Some(name) = member.name.as_ref().filter(|_| member.present) {
+            *self.name_counts.entry(name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// For members of the history, who may have been renamed since.
+    pub fn update_if_unknown(&mut self, user_id: UserId, name: Option<String>, present: bool) {
+        if !self.members.contains_key(&user_id) {
+            self.update(user_id, name, present);
+        }
+    }
+
+    /// The joined and invited members, with their display name if they have one.
+    pub fn present(&self) -> impl Iterator<Item = (&UserId, Option<&str>)> {
+        self.members
+            .iter()
+            .filter(|(_, member)| member.present)
+            .map(|(user_id, member)| (user_id, member.name.as_deref()))
+    }
+
+    /// The display name of the member, followed by their id if another member has the same one.
+    pub fn display_name(&self, user_id: &UserId) -> Option<Cow<'_, str>> {
+        let name = self.members.get(user_id)?.name.as_deref()?;
+        if self.name_counts.get(name).map_or(false, |count| *count > 1) {
+            Some(Cow::Owned(format!("{} ({})", name, user_id)))
+        } else {
+            Some(Cow::Borrowed(name))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Room {
     pub name: String,
@@ -48,6 +482,15 @@ pub struct Room {
     // TODO maybe just always get it from the SDK
     pub notifications: u64,
     pub prev_batch: Option<String>,
+    // what was being typed before switching to another room
+    pub draft: Draft,
+    pub reactions: Reactions,
+    /// The replies of each thread, by the id of the message that started it.
+    pub threads: HashMap<EventId, MessageList>,
+    /// The thread shown next to the timeline, where the keys act and messages are sent.
+    pub open_thread: Option<EventId>,
+    pub show_state_changes: bool,
+    pub members: Members,
 }
 
 impl Room {
@@ -58,25 +501,149 @@ impl Room {
             message_list: MessageList::new(),
             notifications,
             prev_batch,
+            draft: Draft::default(),
+            reactions: Reactions::default(),
+            threads: HashMap::new(),
+            open_thread: None,
+            show_state_changes: true,
+            members: Members::default(),
+        }
+    }
+
+    pub fn push_state_change(&mut self, change: StateChange, old: bool, group: bool) {
+        if self.show_state_changes {
+            let id = self.id.clone();
+            self.message_list.push_state_change(id, change, old, group);
+        }
+    }
+
+    fn lists_mut(&mut self) -> impl Iterator<Item = &mut MessageList> {
+        std::iter::once(&mut self.message_list).chain(self.threads.values_mut())
+    }
+
+    /// The list of the event: its thread, or the one of the message it edits.
+    fn list_of(
+        &mut self,
+        event: &MessageEvent<MessageEventContent>,
+        thread_id: Option<EventId>,
+    ) -> &mut MessageList {
+        let thread_id = thread_id.or_else(|| {
+            let replaced_id = replaced_id(event)?;
+            self.threads
+                .iter()
+                .find(|(_, thread)| thread.find(replaced_id).is_some())
+                .map(|(id, _)| id.clone())
+        });
+        match thread_id {
+            Some(id) => self.threads.entry(id).or_insert_with(MessageList::new),
+            None => &mut self.message_list,
         }
     }
+
+    pub fn push_new(
+        &mut self,
+        event: MessageEvent<MessageEventContent>,
+        thread_id: Option<EventId>,
+    ) {
+        self.reactions.message_loaded(&event.event_id);
+        self.list_of(&event, thread_id).push_new(event)
+    }
+
+    pub fn push_old(
+        &mut self,
+        event: MessageEvent<MessageEventContent>,
+        thread_id: Option<EventId>,
+    ) {
+        self.reactions.message_loaded(&event.event_id);
+        self.list_of(&event, thread_id).push_old(event)
+    }
+
+    pub fn add_reaction(
+        &mut self,
+        reaction_id: EventId,
+        event_id: EventId,
+        user_id: UserId,
+        emoji: String,
+    ) {
+        let loaded = self.find_message(&event_id).is_some();
+        self.reactions
+            .add(reaction_id, event_id, user_id, emoji, loaded);
+    }
+
+    /// Looks for a message in the timeline and in the threads.
+    pub fn find_message(&self, event_id: &EventId) -> Option<&Message> {
+        self.message_list.find(event_id).or_else(|| {
+            self.threads
+                .values()
+                .find_map(|thread| thread.find(event_id))
+        })
+    }
+
+    pub fn find_message_mut(&mut self, event_id: &EventId) -> Option<&mut Message> {
+        self.lists_mut().find_map(|list| list.find_mut(event_id))
+    }
+
+    pub fn local_echo_mut(&mut self, txn_id: &str) -> Option<&mut Message> {
+        self.lists_mut()
+            .find_map(|list| list.local_echo_mut(txn_id))
+    }
+
+    pub fn remove_local_echo(&mut self, txn_id: &str) {
+        for list in self.lists_mut() {
+            list.remove_local_echo(txn_id);
+        }
+    }
+
+    /// The open thread, or the timeline.
+    pub fn selected_list(&self) -> &MessageList {
+        self.open_thread
+            .as_ref()
+            .and_then(|id| self.threads.get(id))
+            .unwrap_or(&self.message_list)
+    }
+
+    pub fn selected_list_mut(&mut self) -> &mut MessageList {
+        match &self.open_thread {
+            Some(id) => self
+                .threads
+                .entry(id.clone())
+                .or_insert_with(MessageList::new),
+            None => &mut self.message_list,
+        }
+    }
+
+    /// The selected message of the open thread, or of the timeline.
+    pub fn selected_message(&self) -> Option<&Message> {
+        self.selected_list().selected()
+    }
 }
 
 pub struct State {
     pub user_id: UserId,
-    pub input: String,
+    pub config: UiConfig,
+    pub highlighter: Highlighter,
+    pub input: Editor,
+    pub status: String,
     pub layout: crate::ui::MientLayout,
     pub current_room_index: usize,
-    pub users: HashMap<UserId, String>,
     pub rooms: Vec<Room>,
-    pub reactions: HashMap<EventId, HashMap<String, HashSet<UserId>>>,
+    pub emoji_picker: Option<EmojiPicker>,
+    /// Most recently used first.
+    pub recent_emoji: Vec<String>,
+    pub logout: bool,
+    pub external_editor: bool,
+    /// The message of the current room that is being edited in the input.
+    pub editing: Option<EventId>,
+    pub send_queue: SendQueue,
 }
 
 impl State {
     pub async fn new(
         client: matrix_sdk::Client,
+        config: UiConfig,
         tx: tokio::sync::mpsc::UnboundedSender<MatrixEvent>,
         terminal_size: tui::layout::Rect,
+        store_path: &str,
     ) -> Self {
         let mut rooms = Vec::new();
         for room in client.joined_rooms() {
@@ -91,6 +658,8 @@ impl State {
                 room.unread_notification_counts().notification_count,
                 prev_batch,
             );
+            mient_room.show_state_changes =
+                config.shows_state_changes(room.room_id(), room.canonical_alias().as_ref());
 
             crate::matrix::fetch_old_messages(
                 room.room_id().clone(),
@@ -98,16 +667,40 @@ impl State {
                 client.clone(),
                 tx.clone(),
             );
+            crate::matrix::fetch_members(room.clone(), tx.clone());
             rooms.push(mient_room);
         }
+        let user_id = client.user_id().await.unwrap();
+        // what wasn't sent before quitting is sent again
+        let mut send_queue = SendQueue::new(client.clone(), tx, store_path);
+        for message in send_queue.pending() {
+            if let Some(room) = rooms.iter_mut().find(|r| r.id == message.room_id) {
+                let echo = message.local_echo(user_id.clone());
+                room.message_list.push_local(echo, message.txn_id.clone());
+            }
+        }
+        send_queue.retry(None);
+        // a broken theme shouldn't prevent reading messages
+        let (highlighter, status) = match Highlighter::new(&config.syntax_theme) {
+            Ok(highlighter) => (highlighter, String::new()),
+            Err(e) => (Highlighter::new(DEFAULT_THEME).unwrap(), e),
+        };
+        let input = Editor::default();
         Self {
-            input: String::new(),
+            layout: crate::ui::make_layout(terminal_size, &input),
+            input,
+            status,
             current_room_index: 0,
-            layout: crate::ui::make_layout(terminal_size),
-            users: HashMap::new(),
             rooms,
-            user_id: client.user_id().await.unwrap(),
-            reactions: HashMap::new(),
+            user_id,
+            config,
+            highlighter,
+            emoji_picker: None,
+            recent_emoji: Vec::new(),
+            logout: false,
+            external_editor: false,
+            editing: None,
+            send_queue,
         }
     }
 
@@ -120,7 +713,6 @@ impl State {
         self.rooms.get_mut(self.current_room_index)
     }
 
-    #[allow(dead_code)]
     pub fn get_room(&self, room_id: &RoomId) -> Option<&Room> {
         for room in &self.rooms {
             if &room.id == room_id {
@@ -140,13 +732,29 @@ impl State {
     }
 
     pub fn change_current_room(&mut self, increment: i32) {
-        self.current_room_index = (self.current_room_index as i32 + increment)
-            .rem_euclid(self.rooms.len() as i32) as usize
+        if self.rooms.is_empty() {
+            return;
+        }
+        let index =
+            (self.current_room_index as i32 + increment).rem_euclid(self.rooms.len() as i32);
+        self.set_current_room(index as usize);
+    }
+
+    /// Switches rooms, keeping what was being typed in the room we leave.
+    pub fn set_current_room(&mut self, index: usize) {
+        self.editing = None;
+        if let Some(room) = self.rooms.get_mut(self.current_room_index) {
+            self.input.swap_draft(&mut room.draft);
+        }
+        self.current_room_index = index;
+        if let Some(room) = self.rooms.get_mut(self.current_room_index) {
+            self.input.swap_draft(&mut room.draft);
+        }
     }
 
     pub fn change_current_message(&mut self, position: ListPosition) {
         if let Some(current_room) = self.current_room_mut() {
-            let message_list = &mut current_room.message_list;
+            let message_list = current_room.selected_list_mut();
             message_list.current_index = match position {
                 ListPosition::First => 0,
                 ListPosition::Last => message_list.messages.len(),
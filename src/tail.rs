@@ -0,0 +1,38 @@
+use crate::events::MatrixEvent;
+use crate::matrix::MatrixBroker;
+
+/// Prints the events received from the sync loop as JSON, one per line.
+pub async fn tail(
+    client: &matrix_sdk::Client,
+    room: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let room_id = match room {
+        Some(room) => Some(crate::send::resolve_room(client, room).await?),
+        None => None,
+    };
+
+    // catch up first so that only the events that happen from now on get printed
+    crate::matrix::sync_once(client).await?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    client
+        .set_event_handler(Box::new(MatrixBroker::new(tx.clone())))
+        .await;
+    crate::app::spawn_matrix_sync_task(client.clone(), MatrixBroker::new(tx));
+
+    while let Some(event) = rx.recv().await {
+        match event {
+            MatrixEvent::OldMessage { .. }
+            | MatrixEvent::PrevBatch { .. }
+            | MatrixEvent::Notifications { .. }
+            | MatrixEvent::StateChange { old: true, .. }
+            | MatrixEvent::Member { .. }
+            | MatrixEvent::Status { .. } => continue,
+            _ => {}
+        }
+        if room_id.is_some() && event.room_id() != room_id.as_ref() {
+            continue;
+        }
+        println!("{}", serde_json::to_string(&event)?);
+    }
+    Ok(())
+}
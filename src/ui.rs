@@ -1,44 +1,107 @@
+use std::borrow::Cow;
+
+use matrix_sdk::ruma::{events::room::message::Relation, RoomId, UserId};
 use tui::style::Modifier;
 use tui::style::Style;
 use tui::text::Text;
 use tui::{backend::Backend, text::Spans};
 use tui::{
     layout::{Constraint, Direction, Layout, Rect},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph},
     Terminal,
 };
 use tui::{style::Color, text::Span};
+use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
+use crate::editor::Editor;
+use crate::highlight::Highlighter;
 use crate::state::Message;
+use crate::state::MessageList;
 use crate::state::Room;
+use crate::state::SendState;
 use crate::state::State;
+use crate::state::StateChange;
+
+/// How many of the grouped membership changes are described before they are expanded.
+const GROUP_SUMMARY_COUNT: usize = 3;
 
 pub struct MientLayout {
     rooms_region: Rect,
     messages_region: Rect,
+    status_region: Rect,
     input_region: Rect,
 }
 
-pub fn make_layout(terminal_size: Rect) -> MientLayout {
+/// Splits the input in lines that fit in `width`, breaking between graphemes. Also returns the row
+/// and column of the cursor.
+fn wrap_input(text: &str, cursor: usize, width: u16) -> (Vec<&str>, (u16, u16)) {
+    let width = width as usize;
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut column = 0;
+    let mut cursor_position = None;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        if grapheme == "\n" {
+            if i == cursor {
+                cursor_position = Some((lines.len(), column));
+            }
+            lines.push(&text[line_start..i]);
+            line_start = i + 1;
+            column = 0;
+            continue;
+        }
+        let grapheme_width = grapheme.width();
+        if column + grapheme_width > width && column > 0 {
+            lines.push(&text[line_start..i]);
+            line_start = i;
+            column = 0;
+        }
+        if i == cursor {
+            cursor_position = Some((lines.len(), column));
+        }
+        column += grapheme_width;
+    }
+    lines.push(&text[line_start..]);
+    let cursor_position = cursor_position.unwrap_or_else(|| {
+        // the cursor is at the end, on the next line if this one is full
+        if column >= width {
+            lines.push("");
+            (lines.len() - 1, 0)
+        } else {
+            (lines.len() - 1, column)
+        }
+    });
+    (lines, (cursor_position.0 as u16, cursor_position.1 as u16))
+}
+
+/// The input region grows with its content, up to a third of the terminal.
+pub fn make_layout(terminal_size: Rect, input: &Editor) -> MientLayout {
     let main_layout = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Length(25), Constraint::Min(1)]) // TODO maybe configurable or resizable
         .split(terminal_size);
 
+    let (input_lines, _) = wrap_input(input.text(), input.cursor(), main_layout[1].width);
+    let input_height = (input_lines.len() as u16).min((terminal_size.height / 3).max(1));
     let right_layout = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .constraints([
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(input_height),
+        ])
         .split(main_layout[1]);
 
     MientLayout {
         rooms_region: main_layout[0],
         messages_region: right_layout[0],
-        input_region: right_layout[1],
+        status_region: right_layout[1],
+        input_region: right_layout[2],
     }
 }
 
-fn color_hash(user: &str) -> Style {
+pub fn color_hash(user: &str) -> Style {
     let hash = user
         .chars()
         .take(2)
@@ -53,44 +116,230 @@ fn color_hash(user: &str) -> Style {
     Style::default().fg(color)
 }
 
-fn format_message<'a>(message: &'a Message, state: &'a State) -> Text<'a> {
-    // TODO users are not really in sync rn
-    let sender = if let Some(sender) = state.users.get(&message.event.sender) {
-        sender
-    } else {
-        message.event.sender.localpart()
+/// The display name of the member in the room, or the localpart of their id.
+fn display_name<'a>(user_id: &'a UserId, room_id: &RoomId, state: &'a State) -> Cow<'a, str> {
+    state
+        .get_room(room_id)
+        .and_then(|room| room.members.display_name(user_id))
+        .unwrap_or(Cow::Borrowed(user_id.localpart()))
+}
+
+/// A dimmed line with the start of the message this one replies to, standing in for the reply
+/// fallback.
+fn format_replied_to<'a>(message: &'a Message, state: &'a State) -> Option<Spans<'a>> {
+    let replied_id = match &message.event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => &in_reply_to.event_id,
+        _ => return None,
+    };
+    let replied_to = state
+        .get_room(&message.event.room_id)?
+        .find_message(replied_id);
+    let style = Style::default().fg(Color::DarkGray);
+    Some(match replied_to {
+        Some(replied_to) => {
+            let body = crate::utils::format_message_body(replied_to.content());
+            let body = strip_plain_reply_fallback(body)
+                .lines()
+                .next()
+                .unwrap_or("");
+            Spans::from(vec![
+                Span::styled("↳ ", style),
+                Span::styled(
+                    display_name(&replied_to.event.sender, &message.event.room_id, state),
+                    style,
+                ),
+                Span::styled(": ", style),
+                Span::styled(body, style),
+            ])
+        }
+        None => Spans::from(Span::styled("↳ in reply to an older message", style)),
+    })
+}
+
+/// A dimmed line with the number of replies in the thread started by the message, if any, and the
+/// start of the last one.
+fn format_thread_summary<'a>(message: &'a Message, state: &'a State) -> Option<Spans<'a>> {
+    let thread = state
+        .get_room(&message.event.room_id)?
+        .threads
+        .get(&message.event.event_id)?;
+    let last = thread.messages.back()?;
+    let count = thread.messages.len();
+    let body = crate::utils::format_message_body(last.content());
+    let body = strip_plain_reply_fallback(body)
+        .lines()
+        .next()
+        .unwrap_or("");
+    let style = Style::default().fg(Color::DarkGray);
+    Some(Spans::from(vec![
+        Span::styled(
+            format!(
+                "└ {} {}, ",
+                count,
+                if count == 1 { "reply" } else { "replies" }
+            ),
+            style,
+        ),
+        Span::styled(
+            display_name(&last.event.sender, &message.event.room_id, state),
+            style,
+        ),
+        Span::styled(": ", style),
+        Span::styled(body, style),
+    ]))
+}
+
+/// Removes the `> ` quote at the start of replies sent without HTML.
+fn strip_plain_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = rest.find('\n').map_or("", |i| &rest[i + 1..]);
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+/// Splits a plain body in lines, highlighting the code blocks fenced with ```.
+fn format_plain_body<'a>(body: &'a str, highlighter: &Highlighter) -> Vec<Spans<'a>> {
+    let mut lines = Vec::new();
+    // the language and content of the code block we are in
+    let mut code_block: Option<(&str, String)> = None;
+    for line in body.split('\n') {
+        let fence = line.trim_start().strip_prefix("```").map(str::trim);
+        match (&mut code_block, fence) {
+            (None, Some(language)) => code_block = Some((language, String::new())),
+            (Some((language, code)), Some("")) => {
+                let language = Some(*language).filter(|l| !l.is_empty());
+                lines.extend(highlighter.code_block(code, language));
+                code_block = None;
+            }
+            (Some((_, code)), _) => {
+                code.push_str(line);
+                code.push('\n');
+            }
+            (None, None) => lines.push(Spans::from(line)),
+        }
+    }
+    // unterminated, but still code
+    if let Some((language, code)) = code_block {
+        let language = Some(language).filter(|l| !l.is_empty());
+        lines.extend(highlighter.code_block(&code, language));
+    }
+    lines
+}
+
+/// State changes are dimmed, those grouped together are summed up on one line unless expanded.
+fn format_state_changes<'a>(message: &'a Message, state: &'a State) -> Text<'a> {
+    let style = Style::default().fg(Color::DarkGray);
+    let describe = |change: &StateChange| {
+        format!(
+            "{} {}",
+            display_name(&change.sender, &message.event.room_id, state),
+            change.text
+        )
     };
-    let body = crate::utils::format_message_body(&message.event.content);
-    let mut text;
-    let mut spans_vec = vec![Span::styled(sender, color_hash(sender)), Span::raw(": ")];
+    let changes = &message.state_changes;
+    if changes.len() == 1 || message.expanded {
+        let lines = changes
+            .iter()
+            .map(|change| Spans::from(Span::styled(describe(change), style)))
+            .collect();
+        return Text { lines };
+    }
+    let mut summary = format!("{} membership changes: ", changes.len());
+    summary.push_str(
+        &changes
+            .iter()
+            .take(GROUP_SUMMARY_COUNT)
+            .map(describe)
+            .collect::<Vec<_>>()
+            .join(", "),
+    );
+    if changes.len() > GROUP_SUMMARY_COUNT {
+        summary.push_str(&format!(
+            " and {} more",
+            changes.len() - GROUP_SUMMARY_COUNT
+        ));
+    }
+    Text::from(Spans::from(Span::styled(summary, style)))
+}
+
+fn format_message<'a>(message: &'a Message, state: &'a State) -> Text<'a> {
+    if message.is_state_change() {
+        return format_state_changes(message, state);
+    }
+    let sender = display_name(&message.event.sender, &message.event.room_id, state);
+    let mut text = Text::default();
+    let replied_to = format_replied_to(message, state);
+    let mut lines = match crate::utils::formatted_message_body(message.content()) {
+        Some(html) => {
+            let members = state.get_room(&message.event.room_id).map(|r| &r.members);
+            crate::html::render(html, members, &state.highlighter)
+        }
+        None => {
+            let mut body = crate::utils::format_message_body(message.content());
+            if replied_to.is_some() {
+                body = strip_plain_reply_fallback(body);
+            }
+            format_plain_body(body, &state.highlighter)
+        }
+    }
+    .into_iter();
+    if let Some(replied_to) = replied_to {
+        text.lines.push(replied_to);
+    }
+    let sender_style = color_hash(&sender);
+    let mut spans_vec = vec![Span::styled(sender, sender_style), Span::raw(": ")];
     if message.redacted {
         spans_vec.push(Span::styled("REDACTED ", Style::default().fg(Color::Red)))
     }
-    if let Some(pos) = body.find('\n') {
-        spans_vec.push(Span::from(&body[..pos]));
-        text = Text::from(Spans::from(spans_vec));
-        text.extend(Text::raw(&body[pos..]));
-    } else {
-        spans_vec.push(Span::from(body));
-        text = Text::from(Spans::from(spans_vec))
+    if let Some(first_line) = lines.next() {
+        spans_vec.extend(first_line.0);
+    }
+    text.lines.push(Spans::from(spans_vec));
+    text.lines.extend(lines);
+    if !message.edits.is_empty() {
+        if let Some(last_line) = text.lines.last_mut() {
+            last_line.0.push(Span::styled(
+                " (edited)",
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+    }
+    let send_state = message.local_echo.as_ref().map(|echo| match &echo.state {
+        SendState::Sending => Span::styled(" (sending)", Style::default().fg(Color::DarkGray)),
+        SendState::Failed(e) => Span::styled(
+            format!(" (failed: {}, Alt-s to retry, Del to cancel)", e),
+            Style::default().fg(Color::Red),
+        ),
+        SendState::Sent => Span::raw(""),
+    });
+    if let (Some(send_state), Some(last_line)) = (send_state, text.lines.last_mut()) {
+        last_line.0.push(send_state);
     }
-    if let Some(reactions) = state.reactions.get(&message.event.event_id) {
+    if let Some(summary) = format_thread_summary(message, state) {
+        text.lines.push(summary);
+    }
+    let reactions = state
+        .get_room(&message.event.room_id)
+        .map(|room| room.reactions.aggregated(&message.event.event_id))
+        .unwrap_or_default();
+    if !reactions.is_empty() {
+        let mut spans_vec = Vec::with_capacity(reactions.len() * 2);
         for (emoji, user_ids) in reactions {
-            let mut spans_vec = Vec::with_capacity(user_ids.len() * 2 + 1);
-            spans_vec.push(Span::styled(emoji, Style::default().fg(Color::Yellow)));
-            for user_id in user_ids {
-                spans_vec.push(Span::styled(" ", Style::default().fg(Color::Yellow)));
-                spans_vec.push(Span::styled(
-                    state
-                        .users
-                        .get(user_id)
-                        .map(|s| s.as_str())
-                        .unwrap_or_else(|| user_id.as_str()),
-                    Style::default().fg(Color::Yellow),
-                ));
+            let mut style = Style::default().fg(Color::Yellow);
+            // our own reactions stand out, as they are the ones we can remove
+            if user_ids.contains(&&state.user_id) {
+                style = style.add_modifier(Modifier::BOLD);
+            }
+            if !spans_vec.is_empty() {
+                spans_vec.push(Span::raw("  "));
             }
-            text.extend(Text::from(Spans::from(spans_vec)))
+            spans_vec.push(Span::styled(format!("{} {}", emoji, user_ids.len()), style));
         }
+        text.lines.push(Spans::from(spans_vec));
     }
     text
 }
@@ -118,44 +367,124 @@ fn render_room_list<T: Backend>(state: &State, frame: &mut tui::Frame<T>) {
     frame.render_stateful_widget(room_list, state.layout.rooms_region, &mut room_list_state);
 }
 
+fn render_messages<T: Backend>(
+    message_list: &MessageList,
+    block: Block,
+    region: Rect,
+    state: &State,
+    frame: &mut tui::Frame<T>,
+) {
+    let messages: Vec<ListItem> = message_list
+        .messages
+        .iter()
+        .map(|message| ListItem::new(format_message(message, &state)))
+        .collect();
+    let list = List::new(messages)
+        .block(block)
+        .highlight_style(Style::default().bg(Color::DarkGray));
+    let mut list_state = ListState::default();
+    list_state.select(Some(message_list.current_index));
+    frame.render_stateful_widget(list, region, &mut list_state);
+}
+
+/// The timeline, and the open thread on its right.
 fn render_message_list<T: Backend>(state: &State, frame: &mut tui::Frame<T>) {
-    if let Some(room) = state.current_room() {
-        let messages: Vec<ListItem> = room
-            .message_list
-            .messages
-            .iter()
-            .map(|message| ListItem::new(format_message(message, &state)))
-            .collect();
-        let message_list = List::new(messages)
-            .block(Block::default().borders(Borders::BOTTOM))
-            .highlight_style(Style::default().bg(Color::DarkGray));
-        let mut message_list_state = ListState::default();
-        message_list_state.select(state.current_room().map(|r| r.message_list.current_index));
-        frame.render_stateful_widget(
-            message_list,
-            state.layout.messages_region,
-            &mut message_list_state,
-        );
-    }
+    let room = match state.current_room() {
+        Some(r) => r,
+        None => return,
+    };
+    let thread = room
+        .open_thread
+        .as_ref()
+        .and_then(|id| Some((id, room.threads.get(id)?)));
+    let (thread_id, thread) = match thread {
+        Some(thread) => thread,
+        None => {
+            let block = Block::default().borders(Borders::BOTTOM);
+            let region = state.layout.messages_region;
+            return render_messages(&room.message_list, block, region, state, frame);
+        }
+    };
+    let regions = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(state.layout.messages_region);
+    let block = Block::default().borders(Borders::BOTTOM);
+    render_messages(&room.message_list, block, regions[0], state, frame);
+
+    let root = room
+        .find_message(thread_id)
+        .map(|msg| crate::utils::format_message_body(msg.content()))
+        .and_then(|body| strip_plain_reply_fallback(body).lines().next())
+        .unwrap_or("");
+    let block = Block::default()
+        .borders(Borders::LEFT | Borders::BOTTOM)
+        .title(format!("Thread: {}", root));
+    render_messages(thread, block, regions[1], state, frame);
+}
+
+fn render_status<T: Backend>(state: &State, frame: &mut tui::Frame<T>) {
+    let status = Paragraph::new(state.status.as_ref()).style(Style::default().fg(Color::Yellow));
+    frame.render_widget(status, state.layout.status_region);
 }
 
 fn render_input<T: Backend>(state: &State, frame: &mut tui::Frame<T>) {
-    let is = state.input.width() as u16;
     let region = state.layout.input_region;
-    let rs = region.width;
-    let input =
-        Paragraph::new(state.input.as_ref()).scroll((0, if is + 1 > rs { is + 1 - rs } else { 0 }));
+    let (lines, (row, column)) = wrap_input(state.input.text(), state.input.cursor(), region.width);
+    // scroll just enough to keep the cursor in view
+    let scroll = if row + 1 > region.height {
+        row + 1 - region.height
+    } else {
+        0
+    };
+    let lines: Vec<Spans> = lines.into_iter().map(Spans::from).collect();
+    let input = Paragraph::new(lines).scroll((scroll, 0));
     frame.render_widget(input, region);
-    frame.set_cursor(region.x + state.input.width() as u16, region.y);
+    frame.set_cursor(region.x + column, region.y + row - scroll);
+}
+
+/// A popup above the input with the emoji matching the search.
+fn render_emoji_picker<T: Backend>(state: &State, frame: &mut tui::Frame<T>) {
+    let picker = match &state.emoji_picker {
+        Some(p) => p,
+        None => return,
+    };
+    let candidates = picker.candidates(&state.recent_emoji);
+    let items: Vec<ListItem> = candidates
+        .iter()
+        .map(|(name, emoji)| ListItem::new(format!("{} {}", emoji, name)))
+        .collect();
+    let messages_region = state.layout.messages_region;
+    let height = (items.len() as u16 + 2).min(12).min(messages_region.height);
+    let width = 30.min(messages_region.width);
+    let region = Rect::new(
+        messages_region.x,
+        messages_region.y + messages_region.height - height,
+        width,
+        height,
+    );
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("React: {}", picker.query)),
+        )
+        .highlight_style(Style::default().bg(Color::DarkGray));
+    let mut list_state = ListState::default();
+    list_state.select(Some(picker.index).filter(|_| !candidates.is_empty()));
+    frame.render_widget(Clear, region);
+    frame.render_stateful_widget(list, region, &mut list_state);
 }
 
 pub fn draw<T: Backend>(terminal: &mut Terminal<T>, state: &mut State) -> std::io::Result<()> {
     terminal
         .draw(|f| {
-            state.layout = make_layout(f.size());
+            state.layout = make_layout(f.size(), &state.input);
             render_room_list(&state, f);
             render_message_list(&state, f);
+            render_status(&state, f);
             render_input(&state, f);
+            render_emoji_picker(&state, f);
         })
         .and(Ok(()))
 }
@@ -1,7 +1,40 @@
 use matrix_sdk::ruma::{
-    events::room::message::{MessageEventContent, MessageType},
-    UserId,
+    events::{
+        custom::CustomEventContent,
+        room::message::{
+            EmoteMessageEventContent, FormattedBody, InReplyTo, MessageEventContent, MessageFormat,
+            MessageType, NoticeMessageEventContent, Relation, Replacement, TextMessageEventContent,
+        },
+        AnyMessageEventContent, MessageEvent,
+    },
+    EventId, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
+use serde_json::Value as JsonValue;
+
+const THREAD_REL_TYPE: &str = "m.thread";
+/// Used before threads made it into the spec.
+const UNSTABLE_THREAD_REL_TYPE: &str = "io.element.thread";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Text,
+    Notice,
+    Emote,
+}
+
+impl MessageKind {
+    /// The kind of a message we could have sent, `None` for files, images and the like.
+    pub fn of(content: &MessageEventContent) -> Option<Self> {
+        match content.msgtype {
+            MessageType::Text(_) => Some(MessageKind::Text),
+            MessageType::Notice(_) => Some(MessageKind::Notice),
+            MessageType::Emote(_) => Some(MessageKind::Emote),
+            _ => None,
+        }
+    }
+}
 
 pub fn format_message_body<'a>(content: &'a MessageEventContent) -> &'a str {
     use MessageType::*;
@@ -20,6 +53,58 @@ pub fn format_message_body<'a>(content: &'a MessageEventContent) -> &'a str {
     }
 }
 
+/// The HTML body of the message, if it has one.
+pub fn formatted_message_body(content: &MessageEventContent) -> Option<&str> {
+    let formatted = match &content.msgtype {
+        MessageType::Emote(content) => &content.formatted,
+        MessageType::Notice(content) => &content.formatted,
+        MessageType::Text(content) => &content.formatted,
+        _ => &None,
+    };
+    formatted
+        .as_ref()
+        .filter(|f| f.format == MessageFormat::Html)
+        .map(|f| f.body.as_str())
+}
+
+/// Removes the reply fallback of an HTML body, what is in the `<mx-reply>` tag.
+pub fn strip_reply_fallback(html: &str) -> String {
+    match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &html[..start], &html[end + "</mx-reply>".len()..])
+        }
+        _ => html.to_owned(),
+    }
+}
+
+/// The HTML version of `format_reply_content`, the quote being in a `<mx-reply>` tag for the
+/// clients that render replies themselves.
+pub fn format_html_reply_content(
+    replied_to: &MessageEvent<MessageEventContent>,
+    reply_html: &str,
+) -> String {
+    let quoted_html = match formatted_message_body(&replied_to.content) {
+        Some(html) => strip_reply_fallback(html),
+        None => escape_html(format_message_body(&replied_to.content))
+            .lines()
+            // skip quoted content, those are previous replied_to
+            .skip_while(|s| s.starts_with("&gt;"))
+            .collect::<Vec<_>>()
+            .join("<br>"),
+    };
+    format!(
+        "<mx-reply><blockquote>\
+         <a href=\"https://matrix.to/#/{room}/{event}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br>{quote}\
+         </blockquote></mx-reply>{reply}",
+        room = replied_to.room_id,
+        event = replied_to.event_id,
+        sender = replied_to.sender,
+        quote = quoted_html,
+        reply = reply_html,
+    )
+}
+
 pub fn format_reply_content(
     replied_to_content: &MessageEventContent,
     sender: &UserId,
@@ -34,3 +119,226 @@ pub fn format_reply_content(
         .join("\n");
     format!("> <{}> {}\n\n{}", sender, quoted_replied, reply)
 }
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes what markdown would format, for text put in a link.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '[' | ']' | '*' | '_' | '`' | '~' | '<') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape_markdown(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Replaces the markdown links to users inserted by completion with their text, for the plain
+/// body of messages.
+pub fn mention_links_to_names(text: &str) -> String {
+    const LINK: &str = "](https://matrix.to/#/";
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(link_start) = rest.find(LINK) {
+        // the name escapes its own brackets
+        let name_start = rest[..link_start]
+            .char_indices()
+            .rev()
+            .find(|&(i, c)| c == '[' && !rest[..i].ends_with('\\'))
+            .map(|(i, _)| i);
+        let link_end = rest[link_start..].find(')').map(|i| link_start + i);
+        match (name_start, link_end) {
+            (Some(name_start), Some(link_end)) => {
+                plain.push_str(&rest[..name_start]);
+                plain.push_str(&unescape_markdown(&rest[name_start + 1..link_start]));
+                rest = &rest[link_end + 1..];
+            }
+            _ => {
+                plain.push_str(&rest[..link_start + LINK.len()]);
+                rest = &rest[link_start + LINK.len()..];
+            }
+        }
+    }
+    plain.push_str(rest);
+    plain
+}
+
+/// Renders CommonMark to HTML, returns `None` if the HTML wouldn't add anything to the plain text.
+pub fn markdown_to_html(text: &str) -> Option<String> {
+    use pulldown_cmark::{html, Options, Parser};
+    let mut html = String::new();
+    html::push_html(
+        &mut html,
+        Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES),
+    );
+    let html = html.trim_end();
+    // a lone paragraph is how plain text gets rendered
+    let unwrapped = html
+        .strip_prefix("<p>")
+        .and_then(|h| h.strip_suffix("</p>"))
+        .filter(|h| !h.contains("<p>"));
+    match unwrapped {
+        Some(h) if h == escape_html(text) => None,
+        Some(h) => Some(h.to_owned()),
+        None => Some(html.to_owned()),
+    }
+}
+
+/// Builds the content of a message, `markdown` being whether `text` should be rendered to HTML.
+/// Replies get both a plain and an HTML fallback.
+pub fn make_message_content(
+    text: &str,
+    kind: MessageKind,
+    markdown: bool,
+    replied_to: Option<&MessageEvent<MessageEventContent>>,
+) -> MessageEventContent {
+    let (mut body, mut html) = if markdown {
+        (mention_links_to_names(text), markdown_to_html(text))
+    } else {
+        (text.to_owned(), None)
+    };
+    let mut relates_to = None;
+    if let Some(msg) = replied_to {
+        relates_to = Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(msg.event_id.clone()),
+        });
+        let reply_html = html.unwrap_or_else(|| escape_html(&body).replace('\n', "<br>"));
+        html = Some(format_html_reply_content(msg, &reply_html));
+        body = format_reply_content(&msg.content, &msg.sender, &body);
+    }
+
+    let mut content = MessageEventContent::new(make_msgtype(kind, body, html));
+    content.relates_to = relates_to;
+    content
+}
+
+fn make_msgtype(kind: MessageKind, body: String, html: Option<String>) -> MessageType {
+    let formatted = html.map(FormattedBody::html);
+    match kind {
+        MessageKind::Text => {
+            let mut content = TextMessageEventContent::plain(body);
+            content.formatted = formatted;
+            MessageType::Text(content)
+        }
+        MessageKind::Notice => {
+            let mut content = NoticeMessageEventContent::plain(body);
+            content.formatted = formatted;
+            MessageType::Notice(content)
+        }
+        MessageKind::Emote => {
+            let mut content = EmoteMessageEventContent::plain(body);
+            content.formatted = formatted;
+            MessageType::Emote(content)
+        }
+    }
+}
+
+/// Builds an `m.replace` edit of `original`, clients that don't know about edits show the new text
+/// prefixed with `* `. Returns `None` if `original` isn't a text message.
+pub fn make_edit_content(
+    text: &str,
+    markdown: bool,
+    original: &MessageEvent<MessageEventContent>,
+) -> Option<MessageEventContent> {
+    let kind = MessageKind::of(&original.content)?;
+    let new_content = make_message_content(text, kind, markdown, None);
+    let body = format!("* {}", format_message_body(&new_content));
+    let html = formatted_message_body(&new_content).map(|html| format!("* {}", html));
+    let mut content = MessageEventContent::new(make_msgtype(kind, body, html));
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        original.event_id.clone(),
+    )));
+    content.new_content = Some(Box::new(new_content));
+    Some(content)
+}
+
+/// Where a message sent in a thread goes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadRelation {
+    /// The message that started the thread.
+    pub thread_id: EventId,
+    /// What clients without threads show it as a reply to, unless it is already a reply.
+    pub fallback_id: Option<EventId>,
+}
+
+/// The thread an event is in. ruma drops the relations it doesn't know, so this reads the JSON.
+pub fn thread_id(event: &RawJsonValue) -> Option<EventId> {
+    #[derive(Deserialize)]
+    struct Event {
+        content: Content,
+    }
+    #[derive(Deserialize)]
+    struct Content {
+        #[serde(rename = "m.relates_to")]
+        relates_to: Option<RelatesTo>,
+    }
+    #[derive(Deserialize)]
+    struct RelatesTo {
+        rel_type: Option<String>,
+        event_id: Option<EventId>,
+    }
+
+    let relates_to = serde_json::from_str::<Event>(event.get())
+        .ok()?
+        .content
+        .relates_to?;
+    match relates_to.rel_type.as_deref() {
+        Some(THREAD_REL_TYPE) | Some(UNSTABLE_THREAD_REL_TYPE) => relates_to.event_id,
+        _ => None,
+    }
+}
+
+/// Adds the thread relation to the content, which ruma can't represent, so it is sent as custom
+/// content of the same type.
+pub fn make_thread_content(
+    content: MessageEventContent,
+    thread: &ThreadRelation,
+) -> AnyMessageEventContent {
+    let mut data = match serde_json::to_value(&content) {
+        Ok(JsonValue::Object(data)) => data,
+        _ => return AnyMessageEventContent::RoomMessage(content),
+    };
+    let mut relates_to = match data.remove("m.relates_to") {
+        Some(JsonValue::Object(relates_to)) => relates_to,
+        _ => serde_json::Map::new(),
+    };
+    relates_to.insert("rel_type".to_owned(), THREAD_REL_TYPE.into());
+    relates_to.insert("event_id".to_owned(), thread.thread_id.as_str().into());
+    if let Some(fallback_id) = &thread.fallback_id {
+        relates_to.insert(
+            "m.in_reply_to".to_owned(),
+            serde_json::json!({ "event_id": fallback_id.as_str() }),
+        );
+        relates_to.insert("is_falling_back".to_owned(), true.into());
+    }
+    data.insert("m.relates_to".to_owned(), relates_to.into());
+    AnyMessageEventContent::_Custom(CustomEventContent {
+        event_type: "m.room.message".to_owned(),
+        data: data.into_iter().collect(),
+    })
+}
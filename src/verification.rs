@@ -17,10 +17,7 @@ pub async fn verify_device(
         .get_device(&client.user_id().await.unwrap(), device.into())
         .await?
     {
-        None => {
-            eprintln!("Device not found");
-            return Ok(()); // TODO should be an Err...
-        }
+        None => return Err(format!("Device {} not found", device).into()),
         Some(d) => d,
     };
     if device.is_locally_trusted() {
@@ -69,10 +66,10 @@ pub async fn verify_device(
 
     if device.is_locally_trusted() {
         println!("Success!");
+        Ok(())
     } else {
-        println!("The device is still untrusted..?");
+        Err("The device is still untrusted..?".into())
     }
-    Ok(())
 }
 
 async fn verify_mac(e: ToDeviceEvent<MacToDeviceEventContent>, client: &matrix_sdk::Client) -> () {